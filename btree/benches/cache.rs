@@ -0,0 +1,68 @@
+//! Time building a k-mer BTree from test5.gbk without a cache, and with caches of increasing size using each eviction policy.
+//! Run with `cargo bench -p btree --bench cache`, optionally followed by `-- <sequence-length> <cache-size>...`
+use std::time::{Duration, Instant};
+use btree::{BTree, BTreeOptions, CachePolicy};
+
+const GBK_FILE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../data/geneBankFiles/test5.gbk");
+
+/// DNA sequences between every ORIGIN and // in a gbk file, split at anything that isn't a, c, g or t
+fn parse_gbk(gbk_file: &str) -> Vec<String> {
+    let hay = std::fs::read_to_string(gbk_file).expect("Couldn't read gbk file");
+    hay.split("ORIGIN").skip(1)
+        .flat_map(|section| {
+            let origin = section.split("//").next().unwrap_or_default();
+            origin.chars()
+                .filter(|c| !c.is_ascii_digit() && !c.is_whitespace())
+                .collect::<String>()
+                .split(|c| !"acgtACGT".contains(c))
+                .map(|sequence| sequence.to_string())
+                .collect::<Vec<String>>()
+        })
+        .filter(|sequence| !sequence.is_empty())
+        .collect()
+}
+
+/// Insert every k-mer into a new BTree, returning how long it took and the BTree's degree
+fn build(kmers: &[u64], use_cache: bool, cache_size: u32, cache_policy: CachePolicy) -> (Duration, u32) {
+    let file_name = std::env::temp_dir().join("btree_cache_bench.btree");
+    let file_name = file_name.to_str().unwrap();
+    let options = BTreeOptions { use_cache, cache_size, cache_policy, truncate_file: true, ..Default::default() };
+    let start = Instant::now();
+    let mut btree: BTree = BTree::open(file_name, options).unwrap();
+    let degree = btree.get_degree();
+    for &kmer in kmers {
+        btree.insert(kmer).unwrap();
+    }
+    drop(btree);
+    let elapsed = start.elapsed();
+    std::fs::remove_file(file_name).ok();
+    (elapsed, degree)
+}
+
+fn main() {
+    // cargo bench passes --bench through to the binary
+    let args: Vec<u32> = std::env::args().skip(1).filter_map(|arg| arg.parse().ok()).collect();
+    let sequence_length = args.first().copied().unwrap_or(20) as usize;
+    let cache_sizes = if args.len() > 1 { args[1..].to_vec() } else { vec![100, 500, 1000, 5000, 10000] };
+    let kmers: Vec<u64> = parse_gbk(GBK_FILE).iter()
+        .flat_map(|sequence| {
+            (0..(sequence.len() + 1).saturating_sub(sequence_length))
+                .map(|i| gene::sequence_to_bin(&sequence[i..i + sequence_length]))
+                .collect::<Vec<u64>>()
+        })
+        .collect();
+    println!("test5.gbk, {} k-mers of length {sequence_length}", kmers.len());
+    let policies = [CachePolicy::Lru, CachePolicy::Clock, CachePolicy::Arc];
+    let (uncached, degree) = build(&kmers, false, 0, CachePolicy::Lru);
+    println!("degree {degree}, uncached run time {:.2}s, speedup of each policy over it:", uncached.as_secs_f64());
+    println!("| cache size | {} |", policies.map(|policy| format!("{policy:>15}")).join(" | "));
+    println!("| ---------- | {} |", policies.map(|_| "-".repeat(15)).join(" | "));
+    for cache_size in cache_sizes {
+        let times = policies.map(|policy| {
+            let (cached, _) = build(&kmers, true, cache_size, policy);
+            let speedup = uncached.as_secs_f64() / cached.as_secs_f64();
+            format!("{:>6.2}s {speedup:>6.2}x", cached.as_secs_f64())
+        });
+        println!("| {cache_size:>10} | {} |", times.join(" | "));
+    }
+}
@@ -0,0 +1,236 @@
+use std::cell::RefCell;
+use std::ops::Bound;
+use std::rc::Rc;
+use crate::btree_node::{Node, TreeObject};
+use crate::encoding::{Key, Value};
+use crate::error::{Error, Result};
+use crate::BTree;
+
+/// Lazy in order iterator over a BTree's TreeObjects, optionally limited to a range of keys.
+/// Only the path of node offsets from the root down to the current key is held, along with the node
+/// at the end of that path, every other node is read through the cache or pager when it's reached.
+/// Subtrees entirely outside of the range are never read.
+/// Nodes that can't be read are returned as an error, after which the iterator is finished.
+pub struct Iter<'a, K: Key, V: Value> {
+    btree: &'a mut BTree<K, V>,
+    /// Node offsets from the root down, with the index of the next key to return from each node
+    stack: Vec<(u64, usize)>,
+    /// Node at the top of the stack, kept so a leaf isn't read again for every key in it
+    current: Option<Rc<RefCell<Node<K, V>>>>,
+    /// Lower bound to walk down to on the first call to next, so creating the iterator doesn't read anything
+    lower: Option<Bound<K>>,
+    /// Iteration stops at the first key past this bound
+    upper: Bound<K>,
+}
+
+impl<'a, K: Key, V: Value> Iter<'a, K, V> {
+    /// Create iterator positioned before the smallest key in btree within lower and upper
+    pub(crate) fn new(btree: &'a mut BTree<K, V>, lower: Bound<K>, upper: Bound<K>) -> Self {
+        Iter { btree, stack: Vec::new(), current: None, lower: Some(lower), upper }
+    }
+
+    /// Walk down from node at offset to the first key within lower, skipping every key and
+    /// child before it, so only the nodes on one root to leaf path are read.
+    fn seek(&mut self, mut offset: u64, lower: Bound<K>) -> Result<()> {
+        loop {
+            let node = self.btree.read(offset)?;
+            let index = node.borrow().keys.iter().take_while(|obj| match lower {
+                Bound::Included(lower) => obj.key < lower,
+                Bound::Excluded(lower) => obj.key <= lower,
+                Bound::Unbounded => false,
+            }).count();
+            self.stack.push((offset, index));
+            let next_offset = if node.borrow().is_leaf() { None } else { Some(node.borrow().children_ptrs[index]) };
+            self.current = Some(node);
+            match next_offset {
+                Some(child_offset) => offset = child_offset,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Check key hasn't gone past the upper bound of the range
+    fn within_upper(&self, key: &K) -> bool {
+        match &self.upper {
+            Bound::Included(upper) => key <= upper,
+            Bound::Excluded(upper) => key < upper,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// Push node at offset and every leftmost child below it onto the stack
+    fn descend(&mut self, mut offset: u64) -> Result<()> {
+        loop {
+            let node = self.btree.read(offset)?;
+            self.stack.push((offset, 0));
+            let next_offset = if node.borrow().is_leaf() { None } else { Some(node.borrow().children_ptrs[0]) };
+            self.current = Some(node);
+            match next_offset {
+                Some(child_offset) => offset = child_offset,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// End the iteration, returning err as its last item
+    fn fail(&mut self, err: Error) -> Option<Result<TreeObject<K, V>>> {
+        self.stack.clear();
+        self.current = None;
+        Some(Err(err))
+    }
+}
+
+impl<K: Key, V: Value> Iterator for Iter<'_, K, V> {
+    type Item = Result<TreeObject<K, V>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(lower) = self.lower.take() {
+            let root_offset = self.btree.root_node.borrow().offset;
+            if let Err(err) = self.seek(root_offset, lower) {
+                return self.fail(err);
+            }
+        }
+        loop {
+            let (offset, index) = *self.stack.last()?;
+            let node = match self.current.take() {
+                Some(node) => node,
+                None => match self.btree.read(offset) {
+                    Ok(node) => node,
+                    Err(err) => return self.fail(err),
+                },
+            };
+            if index < node.borrow().keys.len() {
+                let obj = node.borrow().keys[index];
+                if !self.within_upper(&obj.key) {
+                    // Every remaining key is larger, nothing left in range
+                    self.stack.clear();
+                    return None;
+                }
+                self.stack.last_mut().unwrap().1 += 1;
+                if node.borrow().is_leaf() {
+                    self.current = Some(node);
+                } else {
+                    // Keys in the child to the right come before the node's next key
+                    let child_offset = node.borrow().children_ptrs[index + 1];
+                    if let Err(err) = self.descend(child_offset) {
+                        return self.fail(err);
+                    }
+                }
+                return Some(Ok(obj));
+            }
+            // Node is exhausted, carry on from its parent
+            self.stack.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Helper function, delete files for test cleanup
+    fn delete_file(file: &str){
+        std::fs::remove_file(file).ok();
+    }
+
+    fn btree(file_name: &str, keys: impl Iterator<Item = u64>) -> BTree {
+        let mut b: BTree = BTree::new(2, file_name, true, 10, true).unwrap();
+        for key in keys {
+            b.insert(key).unwrap();
+        }
+        b
+    }
+
+    #[test]
+    fn test_iter_empty() {
+        let file_name = "test_iter_empty.tmp";
+        delete_file(file_name);
+        let mut b: BTree = BTree::new(2, file_name, true, 10, true).unwrap();
+        assert!(b.iter().next().is_none());
+        delete_file(file_name);
+    }
+
+    /// Keys come back in order with their frequencies, and the iterator never holds more than a root to leaf path.
+    #[test]
+    fn test_iter_in_order() {
+        use rand::seq::SliceRandom;
+        for use_cache in [true, false] {
+            let file_name = "test_iter_in_order.tmp";
+            delete_file(file_name);
+            let mut b: BTree = BTree::new(2, file_name, use_cache, 10, true).unwrap();
+            let mut input: Vec<u64> = (0..500).chain(0..250).collect();
+            input.shuffle(&mut rand::thread_rng());
+            for i in input {
+                b.insert(i).unwrap();
+            }
+            let height = b.get_height() as usize;
+            let mut iter = b.iter();
+            let mut expected = 0;
+            while let Some(obj) = iter.next() {
+                let obj = obj.unwrap();
+                assert!(iter.stack.len() <= height + 1);
+                assert_eq!(expected, obj.key);
+                assert_eq!(if expected < 250 { 2 } else { 1 }, obj.value);
+                expected += 1;
+            }
+            assert_eq!(500, expected);
+            delete_file(file_name);
+        }
+    }
+
+    /// Ranges return the same keys as filtering the whole BTree, for every kind of bound.
+    #[test]
+    fn test_range_bounds() {
+        let file_name = "test_range_bounds.tmp";
+        delete_file(file_name);
+        // Even keys only, so bounds land both on and between stored keys
+        let mut b = btree(file_name, (0..300).map(|i| i * 2));
+        let keys = |iter: Iter<u64, u64>| iter.map(|obj| obj.unwrap().key).collect::<Vec<u64>>();
+        let expected = |lo: u64, hi: u64| (lo..hi).filter(|i| i % 2 == 0).collect::<Vec<u64>>();
+        assert_eq!(expected(100, 200), keys(b.range(100..200)));
+        assert_eq!(expected(101, 201), keys(b.range(101..=200)));
+        assert_eq!(expected(0, 51), keys(b.range(..=50)));
+        assert_eq!(expected(551, 600), keys(b.range(551..)));
+        assert_eq!(expected(0, 600), keys(b.range(..)));
+        assert_eq!(expected(102, 200), keys(b.range((Bound::Excluded(100), Bound::Excluded(200)))));
+        assert!(keys(b.range(41..42)).is_empty());
+        assert!(keys(b.range(1000..)).is_empty());
+        delete_file(file_name);
+    }
+
+    /// A narrow range reads a couple of root to leaf paths, not the whole BTree.
+    #[test]
+    fn test_range_prunes_subtrees() {
+        let file_name = "test_range_prunes_subtrees.tmp";
+        delete_file(file_name);
+        let mut b = btree(file_name, 0..1000);
+        let height = b.get_height() as usize;
+        let mut iter = b.range(500..503);
+        assert_eq!(500, iter.next().unwrap().unwrap().key);
+        assert_eq!(height + 1, iter.stack.len());
+        assert_eq!(vec![501, 502], iter.by_ref().map(|obj| obj.unwrap().key).collect::<Vec<u64>>());
+        assert!(iter.stack.is_empty());
+        delete_file(file_name);
+    }
+
+    /// A node that can't be read ends the iteration with an error, instead of panicking.
+    #[test]
+    fn test_iter_corrupt_node() {
+        use std::io::{Seek, SeekFrom, Write};
+        let file_name = "test_iter_corrupt_node.tmp";
+        delete_file(file_name);
+        let b = btree(file_name, 0..100);
+        let child_offset = b.root_node.borrow().children_ptrs[0];
+        drop(b);
+        // Overwrite the root's first child's offset, so it no longer matches where the node is stored
+        let mut file = std::fs::OpenOptions::new().write(true).open(file_name).unwrap();
+        file.seek(SeekFrom::Start(child_offset)).unwrap();
+        file.write_all(&[0xff; 8]).unwrap();
+        drop(file);
+        let mut b: BTree = BTree::new(2, file_name, false, 0, false).unwrap();
+        let mut iter = b.iter();
+        assert!(matches!(iter.next(), Some(Err(Error::Corrupt { offset, .. })) if offset == child_offset));
+        assert!(iter.next().is_none());
+        delete_file(file_name);
+    }
+}
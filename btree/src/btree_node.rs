@@ -1,28 +1,29 @@
 use std::cmp::Ordering;
+use crate::pager::DEFAULT_PAGE_SIZE;
 
 /// Node Struct, representing every node within btree
 #[derive(Debug)]
-pub struct Node{
+pub struct Node<K = u64, V = u64> {
     pub number_of_keys: u32,
     pub is_leaf: bool,
-    pub offset: u32,
-    pub keys: Vec<TreeObject>,
-    pub children_ptrs: Vec<u32>,
+    pub offset: u64,
+    pub keys: Vec<TreeObject<K, V>>,
+    pub children_ptrs: Vec<u64>,
 }
 
-impl Default for Node {
+impl<K, V> Default for Node<K, V> {
     fn default() -> Self {
         Node {
         number_of_keys: 0,
         is_leaf: true,
-        offset: 8,
+        offset: DEFAULT_PAGE_SIZE as u64,
         keys: Vec::new(),
         children_ptrs: Vec::new(),
         }
     }
 }
 
-impl PartialEq for Node {
+impl<K: PartialEq, V> PartialEq for Node<K, V> {
     fn eq(&self, other: &Self) -> bool {
         self.number_of_keys == other.number_of_keys &&
         self.is_leaf == other.is_leaf &&
@@ -32,14 +33,14 @@ impl PartialEq for Node {
     }
 }
 
-impl Node {
+impl<K, V> Node<K, V> {
     /// Node constructor
-    pub fn new() -> Node {
+    pub fn new() -> Node<K, V> {
         Node {..Default::default()}
     }
 
     /// Add child pointer, represented as node file offset.
-    pub fn add_child_ptr(&mut self, offset: u32) {
+    pub fn add_child_ptr(&mut self, offset: u64) {
         self.children_ptrs.push(offset);
     }
 
@@ -59,40 +60,49 @@ impl Node {
     }
 }
 
-/// TreeObject represents the Key Value pair stored within Btree.
-/// Both Key and Value are unfortunetly coupled together.
-#[derive(Clone, Copy, Debug, Eq)]
-pub struct TreeObject {
-    pub sequence: u64,
-    pub frequency: u64,
+/// TreeObject represents the Key Value pair stored within Btree, ordered and compared by key alone.
+/// The default parameters are the k-mer counter, a 2 bit encoded gene sequence key with its frequency as value.
+#[derive(Clone, Copy, Debug)]
+pub struct TreeObject<K = u64, V = u64> {
+    pub key: K,
+    pub value: V,
 }
 
-impl PartialEq for TreeObject {
+impl<K: PartialEq, V> PartialEq for TreeObject<K, V> {
     fn eq(&self, other: &Self) -> bool {
-        self.sequence == other.sequence
+        self.key == other.key
     }
 }
 
-impl PartialOrd for TreeObject {
+impl<K: Eq, V> Eq for TreeObject<K, V> {}
+
+impl<K: Ord, V> PartialOrd for TreeObject<K, V> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.sequence.partial_cmp(&other.sequence)
+        Some(self.cmp(other))
     }
 }
 
-impl Ord for TreeObject {
+impl<K: Ord, V> Ord for TreeObject<K, V> {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.sequence.cmp(&other.sequence)
+        self.key.cmp(&other.key)
     }
 }
 
-impl TreeObject{
+impl<K, V> TreeObject<K, V> {
     /// Constructor for TreeObject, return Treeobject
-    pub fn new(sequence: u64, frequency: u64) -> Self {
-        TreeObject {sequence, frequency}
+    pub fn new(key: K, value: V) -> Self {
+        TreeObject {key, value}
     }
+}
 
+impl TreeObject {
     /// Increment the frequency up by one
     pub fn increase_frequency(&mut self) {
-        self.frequency += 1;
+        self.value += 1;
     }
-}
\ No newline at end of file
+
+    /// Decrement the frequency down by count, stopping at zero
+    pub fn decrease_frequency(&mut self, count: u64) {
+        self.value = self.value.saturating_sub(count);
+    }
+}
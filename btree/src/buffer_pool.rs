@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+use std::cell::RefCell;
+use crate::btree_node::{Node, TreeObject};
+use crate::cache_policy::{CachePolicy, EvictionPolicy};
+
+/// Counts of how the cache has been used since the BTree was opened, for tuning its size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads of a node found in the cache.
+    pub hits: u64,
+    /// Reads of a node that had to be read from file.
+    pub misses: u64,
+    /// Nodes dropped from a full cache to make room for another.
+    pub evictions: u64,
+    /// Changed nodes written to file, when evicted or flushed.
+    pub writebacks: u64,
+}
+
+impl CacheStats {
+    /// Fraction of reads found in the cache, 0 before anything has been read.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 { 0.0 } else { self.hits as f64 / lookups as f64 }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} hits, {} misses, {:.2}% hit rate, {} evictions, {} writebacks",
+               self.hits, self.misses, self.hit_rate() * 100.0, self.evictions, self.writebacks)
+    }
+}
+
+/// Bytes of memory a node of degree takes up in a pool evicting with policy, the node itself when full,
+/// its Rc and RefCell, its frame and its entries in the HashMap and policy. Several times the node's size
+/// on disk for small degrees, a memory budget is divided by this to get the number of nodes it holds.
+pub fn node_memory<K, V>(degree: u32, policy: CachePolicy) -> u64 {
+    let max_keys = (2 * degree - 1) as usize;
+    // Rc's strong and weak counts come before the RefCell
+    let node = 2 * size_of::<usize>() + size_of::<RefCell<Node<K, V>>>()
+        + max_keys * size_of::<TreeObject<K, V>>() + (max_keys + 1) * size_of::<u64>();
+    // The frames HashMap entry and its control byte
+    let frame = size_of::<(u64, Frame<K, V>)>() + 1;
+    (node + frame) as u64 + policy.entry_memory()
+}
+
+/// Node held by the pool.
+struct Frame<K, V> {
+    node: Rc<RefCell<Node<K, V>>>,
+    /// Node was a leaf when added, deciding which policy tracks it
+    leaf: bool,
+    /// Node has changed since it was last written to file
+    dirty: bool,
+}
+
+/// BufferPool is the one owner of the BTree's nodes in memory, so there is never more than one copy of a
+/// node to diverge. Every node read from file, or created, is added to the pool, and is found by offset
+/// through a HashMap.
+///
+/// A node is pinned for as long as a handle to it, a clone of the pool's Rc, is held outside the pool,
+/// dropping the handle unpins it. Pinned nodes are never evicted, so a node read again while it's still
+/// being changed is always the same instance. Once more than capacity nodes are held, unpinned nodes are
+/// evicted, the pool only goes over capacity while every node is pinned. Leaves and internal nodes are
+/// tracked by separate EvictionPolicies, and leaves are evicted first, so the upper levels of the tree that
+/// every search passes through stay in memory for as long as there's room for them.
+/// Changed nodes are marked dirty, and handed back to be written to file when they're evicted or flushed.
+pub struct BufferPool<K, V> {
+    frames: HashMap<u64, Frame<K, V>>,
+    leaves: Box<dyn EvictionPolicy>,
+    internal: Box<dyn EvictionPolicy>,
+    capacity: u32,
+    stats: CacheStats,
+}
+
+impl<K, V> BufferPool<K, V> {
+    /// Create pool holding capacity unpinned nodes, evicting them with policy
+    pub fn new(capacity: u32, policy: CachePolicy) -> Self {
+        BufferPool {
+            frames: HashMap::new(),
+            leaves: policy.build(capacity),
+            internal: policy.build(capacity),
+            capacity,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Pin the node at offset, returning a handle to it, or None if it has to be read from file and added
+    pub fn pin(&mut self, offset: u64) -> Option<Rc<RefCell<Node<K, V>>>> {
+        let Some(frame) = self.frames.get(&offset) else {
+            self.stats.misses += 1;
+            return None
+        };
+        self.stats.hits += 1;
+        let (node, leaf) = (frame.node.clone(), frame.leaf);
+        self.policy(leaf).access(offset);
+        Some(node)
+    }
+
+    /// Add node to the pool, dirty if it hasn't been written to file yet. A node already in the pool
+    /// is only marked dirty, it has to be the same instance. Nodes are then evicted until the pool is
+    /// back down to capacity, returning the dirty ones, which now have to be written to file.
+    pub fn insert(&mut self, node: Rc<RefCell<Node<K, V>>>, dirty: bool) -> Vec<Rc<RefCell<Node<K, V>>>> {
+        let offset = node.borrow().offset;
+        if let Some(frame) = self.frames.get_mut(&offset) {
+            debug_assert!(Rc::ptr_eq(&frame.node, &node), "Second copy of node {offset} added to the buffer pool");
+            frame.dirty |= dirty;
+            let leaf = frame.leaf;
+            self.policy(leaf).access(offset);
+            return Vec::new()
+        }
+        let leaf = node.borrow().is_leaf();
+        self.frames.insert(offset, Frame { node, leaf, dirty });
+        self.policy(leaf).insert(offset);
+        self.evict()
+    }
+
+    /// Evict unpinned nodes until the pool holds no more than capacity, returning the dirty ones
+    fn evict(&mut self) -> Vec<Rc<RefCell<Node<K, V>>>> {
+        let mut evicted = Vec::new();
+        while self.frames.len() > self.capacity as usize {
+            let frames = &self.frames;
+            let pinned = |offset| frames.get(&offset).is_some_and(|frame| Rc::strong_count(&frame.node) > 1);
+            let Some(victim) = self.leaves.evict(&pinned).or_else(|| self.internal.evict(&pinned)) else {
+                break
+            };
+            let frame = self.frames.remove(&victim).expect("Cache policy evicted a node that isn't in the pool");
+            self.stats.evictions += 1;
+            if frame.dirty {
+                self.stats.writebacks += 1;
+                evicted.push(frame.node);
+            }
+        }
+        evicted
+    }
+
+    /// Every dirty node, in offset order, to be written to file. They're marked clean and stay in the pool.
+    pub fn take_dirty(&mut self) -> Vec<Rc<RefCell<Node<K, V>>>> {
+        let mut dirty: Vec<(&u64, &mut Frame<K, V>)> = self.frames.iter_mut().filter(|(_, frame)| frame.dirty).collect();
+        dirty.sort_by_key(|(&offset, _)| offset);
+        self.stats.writebacks += dirty.len() as u64;
+        dirty.into_iter().map(|(_, frame)| {
+            frame.dirty = false;
+            frame.node.clone()
+        }).collect()
+    }
+
+    /// Remove node with matching offset from the pool, used once a node is freed from the BTree
+    pub fn remove_offset(&mut self, offset: u64) {
+        if let Some(frame) = self.frames.remove(&offset) {
+            self.policy(frame.leaf).remove(offset);
+        }
+    }
+
+    /// Policy tracking leaves or internal nodes
+    fn policy(&mut self, leaf: bool) -> &mut dyn EvictionPolicy {
+        if leaf { self.leaves.as_mut() } else { self.internal.as_mut() }
+    }
+
+    /// Counts of hits, misses and evictions so far
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Number of unpinned nodes held before any are evicted
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    /// Number of nodes held, pinned or not
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(offset: u64) -> Rc<RefCell<Node>> {
+        let mut node = Node::new();
+        node.offset = offset;
+        Rc::new(RefCell::new(node))
+    }
+
+    /// Offsets of every node in the pool, smallest first
+    fn held(pool: &BufferPool<u64, u64>) -> Vec<u64> {
+        let mut offsets: Vec<u64> = pool.frames.keys().copied().collect();
+        offsets.sort();
+        offsets
+    }
+
+    #[test]
+    fn test_pool_evicts_least_recently_used() {
+        let mut pool = BufferPool::new(3, CachePolicy::Lru);
+        for offset in [1, 2, 3] {
+            assert!(pool.insert(node(offset), false).is_empty());
+        }
+        drop(pool.pin(1));
+        assert!(pool.insert(node(4), false).is_empty());
+        assert_eq!(vec![1, 3, 4], held(&pool));
+        assert!(pool.pin(2).is_none());
+        assert_eq!(CacheStats { hits: 1, misses: 1, evictions: 1, writebacks: 0 }, pool.stats());
+        assert_eq!(0.5, pool.stats().hit_rate());
+    }
+
+    #[test]
+    fn test_pool_policies() {
+        for policy in [CachePolicy::Lru, CachePolicy::Clock, CachePolicy::Arc] {
+            let mut pool = BufferPool::new(4, policy);
+            for offset in 0..100 {
+                if pool.pin(offset % 10).is_none() {
+                    pool.insert(node(offset % 10), offset % 3 == 0);
+                }
+                drop(pool.pin(0));
+                assert!(pool.len() <= 4);
+            }
+            // Node 0 is used after every add, so none of the policies evict it
+            assert!(held(&pool).contains(&0), "{policy} evicted the most used node");
+        }
+    }
+
+    #[test]
+    fn test_pool_evicts_leaves_first() {
+        let mut pool = BufferPool::new(3, CachePolicy::Lru);
+        let internal = node(1);
+        internal.borrow_mut().is_leaf = false;
+        pool.insert(internal, false);
+        for offset in [2, 3, 4, 5] {
+            pool.insert(node(offset), false);
+        }
+        // The internal node is least recently used, but leaves go first while there are any unpinned
+        assert_eq!(vec![1, 4, 5], held(&pool));
+        let leaves = [node(6), node(7), node(8)];
+        for leaf in &leaves {
+            pool.insert(leaf.clone(), false);
+        }
+        assert_eq!(vec![6, 7, 8], held(&pool));
+        assert_eq!(5, pool.stats().evictions);
+    }
+
+    #[test]
+    fn test_pool_keeps_pinned_nodes() {
+        let mut pool = BufferPool::new(2, CachePolicy::Lru);
+        let pinned = node(1);
+        pool.insert(pinned.clone(), true);
+        pool.insert(node(2), false);
+        // 1 is least recently used but pinned, so 2 is evicted
+        assert!(pool.insert(node(3), false).is_empty());
+        assert_eq!(vec![1, 3], held(&pool));
+        // The same instance is handed out while it's pinned
+        pinned.borrow_mut().number_of_keys = 5;
+        assert!(Rc::ptr_eq(&pinned, &pool.pin(1).unwrap()));
+        // Every node pinned, so the pool goes over capacity until they're unpinned
+        let handles = [node(4), node(5)];
+        for handle in &handles {
+            assert!(pool.insert(handle.clone(), false).is_empty());
+        }
+        assert_eq!(vec![1, 4, 5], held(&pool));
+        drop(pinned);
+        drop(handles);
+        let evicted = pool.insert(node(6), false);
+        assert_eq!(1, evicted.len());
+        assert_eq!(5, evicted[0].borrow().number_of_keys);
+        assert_eq!(2, pool.len());
+    }
+
+    #[test]
+    fn test_pool_write_back() {
+        let mut pool = BufferPool::new(3, CachePolicy::Lru);
+        pool.insert(node(3), true);
+        pool.insert(node(1), false);
+        pool.insert(node(2), true);
+        // Node 3 was least recently used and dirty, so it comes back to be written
+        let evicted = pool.insert(node(4), false);
+        assert_eq!(3, evicted[0].borrow().offset);
+        // Clean node 1 is dropped without being written
+        assert!(pool.pin(2).is_some());
+        assert!(pool.insert(node(5), true).is_empty());
+        let dirty: Vec<u64> = pool.take_dirty().iter().map(|node| node.borrow().offset).collect();
+        assert_eq!(vec![2, 5], dirty);
+        assert!(pool.take_dirty().is_empty());
+        assert_eq!(3, pool.stats().writebacks);
+        // A pool without room keeps nodes only while they're pinned
+        let mut pool: BufferPool<u64, u64> = BufferPool::new(0, CachePolicy::Lru);
+        let handle = node(1);
+        assert!(pool.insert(handle.clone(), true).is_empty());
+        pool.insert(handle.clone(), true);
+        drop(handle);
+        assert_eq!(1, pool.insert(node(2), false)[0].borrow().offset);
+        assert!(held(&pool).is_empty());
+    }
+
+    #[test]
+    fn test_pool_remove_offset() {
+        let mut pool = BufferPool::new(4, CachePolicy::Lru);
+        for offset in [1, 2, 3, 4] {
+            pool.insert(node(offset), false);
+        }
+        pool.remove_offset(2);
+        pool.remove_offset(42);
+        assert_eq!(vec![1, 3, 4], held(&pool));
+        assert!(pool.pin(2).is_none());
+        // The freed room is used before anything is evicted, then 1 is least recently used
+        assert!(pool.insert(node(5), true).is_empty());
+        assert!(pool.insert(node(6), false).is_empty());
+        assert_eq!(vec![3, 4, 5, 6], held(&pool));
+    }
+}
@@ -0,0 +1,567 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Decides which node a full BufferPool evicts. Policies only track the offsets of the nodes in the pool,
+/// the pool holds the nodes themselves.
+pub trait EvictionPolicy {
+    /// Start tracking a node added to the pool.
+    fn insert(&mut self, offset: u64);
+
+    /// Node at offset was used again.
+    fn access(&mut self, offset: u64);
+
+    /// Pick a node to evict, skipping any pinned node, and stop tracking it.
+    /// Returns None if every node is pinned.
+    fn evict(&mut self, pinned: &dyn Fn(u64) -> bool) -> Option<u64>;
+
+    /// Stop tracking a node removed from the pool without being evicted.
+    fn remove(&mut self, offset: u64);
+}
+
+/// Eviction policies a BTree's cache can be created with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Evict the least recently used node.
+    #[default]
+    Lru,
+    /// Sweep a clock hand over the nodes, evicting the first one not used since the hand last passed it.
+    Clock,
+    /// Adaptive Replacement Cache, balancing recently used nodes against frequently used ones
+    /// by remembering the offsets of recently evicted nodes.
+    Arc,
+}
+
+impl CachePolicy {
+    /// Create the policy for a cache holding capacity nodes.
+    pub fn build(self, capacity: u32) -> Box<dyn EvictionPolicy> {
+        match self {
+            CachePolicy::Lru => Box::new(LruPolicy::new()),
+            CachePolicy::Clock => Box::new(ClockPolicy::new()),
+            CachePolicy::Arc => Box::new(ArcPolicy::new(capacity as usize)),
+        }
+    }
+
+    /// Bytes the policy keeps for each node in the cache at most, an offset in a list and in the HashMap
+    /// indexing it. ARC also remembers evicted offsets, up to a full cache's worth in each of the pool's
+    /// leaf and internal policies.
+    pub fn entry_memory(self) -> u64 {
+        // HashMap entries also take a control byte
+        let index_entry = size_of::<(u64, usize)>() + 1;
+        let entry = match self {
+            CachePolicy::Lru => size_of::<Link>() + index_entry,
+            CachePolicy::Clock => size_of::<(u64, bool)>() + index_entry,
+            CachePolicy::Arc => 3 * (size_of::<Link>() + index_entry),
+        };
+        entry as u64
+    }
+}
+
+impl fmt::Display for CachePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            CachePolicy::Lru => "lru",
+            CachePolicy::Clock => "clock",
+            CachePolicy::Arc => "arc",
+        })
+    }
+}
+
+impl FromStr for CachePolicy {
+    type Err = String;
+
+    /// Parse a policy name, lru, clock or arc in any case.
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "lru" => Ok(CachePolicy::Lru),
+            "clock" => Ok(CachePolicy::Clock),
+            "arc" => Ok(CachePolicy::Arc),
+            _ => Err(format!("Unknown cache policy {name}, expected lru, clock or arc")),
+        }
+    }
+}
+
+/// Marks either end of an LruList.
+const NIL: usize = usize::MAX;
+
+/// Offset in an LruList, linked to the offsets used just before and after it by their index in links.
+struct Link {
+    offset: u64,
+    prev: usize,
+    next: usize,
+}
+
+/// Offsets ordered from most to least recently used. Offsets are found through a HashMap, and the
+/// list is threaded through the links Vec, so every operation is constant time
+/// apart from last_unpinned, which steps over pinned offsets.
+struct LruList {
+    index: HashMap<u64, usize>,
+    links: Vec<Link>,
+    /// Most recently used offset
+    head: usize,
+    /// Least recently used offset
+    tail: usize,
+}
+
+impl LruList {
+    fn new() -> Self {
+        LruList { index: HashMap::new(), links: Vec::new(), head: NIL, tail: NIL }
+    }
+
+    fn len(&self) -> usize {
+        self.links.len()
+    }
+
+    fn contains(&self, offset: u64) -> bool {
+        self.index.contains_key(&offset)
+    }
+
+    /// Add offset as the most recently used, it mustn't already be in the list
+    fn push_front(&mut self, offset: u64) {
+        self.links.push(Link { offset, prev: NIL, next: NIL });
+        let index = self.links.len() - 1;
+        self.index.insert(offset, index);
+        self.link_front(index);
+    }
+
+    /// Mark offset as the most recently used, returns false if it isn't in the list
+    fn move_to_front(&mut self, offset: u64) -> bool {
+        let Some(&index) = self.index.get(&offset) else {
+            return false
+        };
+        if self.head != index {
+            self.unlink(index);
+            self.link_front(index);
+        }
+        true
+    }
+
+    /// Remove and return the least recently used offset
+    fn pop_back(&mut self) -> Option<u64> {
+        if self.tail == NIL {
+            return None
+        }
+        let offset = self.links[self.tail].offset;
+        self.remove(offset);
+        Some(offset)
+    }
+
+    /// Least recently used offset that isn't pinned
+    fn last_unpinned(&self, pinned: &dyn Fn(u64) -> bool) -> Option<u64> {
+        let mut index = self.tail;
+        while index != NIL {
+            let offset = self.links[index].offset;
+            if !pinned(offset) {
+                return Some(offset)
+            }
+            index = self.links[index].prev;
+        }
+        None
+    }
+
+    /// Remove offset, returns false if it isn't in the list.
+    /// The last link is moved into the removed link's place so links stays packed.
+    fn remove(&mut self, offset: u64) -> bool {
+        let Some(index) = self.index.remove(&offset) else {
+            return false
+        };
+        self.unlink(index);
+        self.links.swap_remove(index);
+        if index < self.links.len() {
+            let (prev, next, moved) = (self.links[index].prev, self.links[index].next, self.links[index].offset);
+            if prev == NIL { self.head = index } else { self.links[prev].next = index }
+            if next == NIL { self.tail = index } else { self.links[next].prev = index }
+            self.index.insert(moved, index);
+        }
+        true
+    }
+
+    /// Take link at index out of the list, leaving it in links
+    fn unlink(&mut self, index: usize) {
+        let (prev, next) = (self.links[index].prev, self.links[index].next);
+        if prev == NIL { self.head = next } else { self.links[prev].next = next }
+        if next == NIL { self.tail = prev } else { self.links[next].prev = prev }
+    }
+
+    /// Put an unlinked link at the front of the list
+    fn link_front(&mut self, index: usize) {
+        self.links[index].prev = NIL;
+        self.links[index].next = self.head;
+        if self.head == NIL { self.tail = index } else { self.links[self.head].prev = index }
+        self.head = index;
+    }
+}
+
+/// Least recently used, every use moves a node to the front and the node at the back is evicted.
+pub struct LruPolicy {
+    list: LruList,
+}
+
+impl LruPolicy {
+    pub fn new() -> Self {
+        LruPolicy { list: LruList::new() }
+    }
+}
+
+impl Default for LruPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EvictionPolicy for LruPolicy {
+    fn insert(&mut self, offset: u64) {
+        self.list.push_front(offset);
+    }
+
+    fn access(&mut self, offset: u64) {
+        self.list.move_to_front(offset);
+    }
+
+    fn evict(&mut self, pinned: &dyn Fn(u64) -> bool) -> Option<u64> {
+        let victim = self.list.last_unpinned(pinned)?;
+        self.list.remove(victim);
+        Some(victim)
+    }
+
+    fn remove(&mut self, offset: u64) {
+        self.list.remove(offset);
+    }
+}
+
+/// CLOCK, an approximation of LRU where a use only sets a node's referenced bit.
+/// A hand sweeps round the nodes clearing referenced bits, and evicts the first node it finds without one,
+/// so hot nodes like the upper levels of the tree keep getting second chances.
+pub struct ClockPolicy {
+    /// Offsets in the order the hand visits them, with their referenced bit
+    slots: Vec<(u64, bool)>,
+    /// Index into slots of each offset
+    index: HashMap<u64, usize>,
+    /// Next slot to be looked at
+    hand: usize,
+}
+
+impl ClockPolicy {
+    pub fn new() -> Self {
+        ClockPolicy { slots: Vec::new(), index: HashMap::new(), hand: 0 }
+    }
+}
+
+impl Default for ClockPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EvictionPolicy for ClockPolicy {
+    fn insert(&mut self, offset: u64) {
+        self.index.insert(offset, self.slots.len());
+        self.slots.push((offset, false));
+    }
+
+    fn access(&mut self, offset: u64) {
+        if let Some(&slot) = self.index.get(&offset) {
+            self.slots[slot].1 = true;
+        }
+    }
+
+    fn evict(&mut self, pinned: &dyn Fn(u64) -> bool) -> Option<u64> {
+        // Every unpinned slot is cleared within one sweep, so this finds a victim within two
+        for _ in 0..2 * self.slots.len() {
+            self.hand %= self.slots.len();
+            let (offset, referenced) = self.slots[self.hand];
+            if !pinned(offset) {
+                if !referenced {
+                    // The last slot moves into the victim's, the hand passes over it like a newly added node
+                    self.remove(offset);
+                    self.hand += 1;
+                    return Some(offset)
+                }
+                self.slots[self.hand].1 = false;
+            }
+            self.hand += 1;
+        }
+        None
+    }
+
+    fn remove(&mut self, offset: u64) {
+        let Some(slot) = self.index.remove(&offset) else {
+            return
+        };
+        self.slots.swap_remove(slot);
+        if let Some(&(moved, _)) = self.slots.get(slot) {
+            self.index.insert(moved, slot);
+        }
+    }
+}
+
+/// Adaptive Replacement Cache (Megiddo and Modha). Cached nodes are split between t1, used once
+/// since they were added, and t2, used more than once. The offsets of nodes evicted from each are
+/// remembered in the ghost lists b1 and b2. A miss on a ghost offset shows that list was evicting too
+/// early, and moves the target size p of t1 towards it, so the cache adapts between favouring
+/// recently and frequently used nodes.
+pub struct ArcPolicy {
+    capacity: usize,
+    /// Target size of t1
+    p: usize,
+    t1: LruList,
+    t2: LruList,
+    b1: LruList,
+    b2: LruList,
+    /// Offset last inserted and whether it was found in b2, the eviction making room for it is decided as if it
+    /// wasn't in the pool yet. Taken by that eviction, so one that comes later, from another policy's insert,
+    /// doesn't see it
+    incoming: Option<(u64, bool)>,
+}
+
+impl ArcPolicy {
+    pub fn new(capacity: usize) -> Self {
+        ArcPolicy {
+            capacity,
+            p: 0,
+            t1: LruList::new(),
+            t2: LruList::new(),
+            b1: LruList::new(),
+            b2: LruList::new(),
+            incoming: None,
+        }
+    }
+
+    /// Evict the least recently used unpinned offset in list, remembering it in ghost
+    fn evict_from(list: &mut LruList, ghost: &mut LruList, pinned: &dyn Fn(u64) -> bool) -> Option<u64> {
+        let victim = list.last_unpinned(pinned)?;
+        list.remove(victim);
+        ghost.push_front(victim);
+        Some(victim)
+    }
+
+    /// Forget the oldest ghosts until t1 and b1 hold at most capacity offsets, and all four lists twice capacity.
+    /// Pinned nodes can keep more than capacity nodes in the pool, so the ghost lists are bounded after every
+    /// eviction, and a pool without a cache keeps no ghosts at all.
+    fn trim_ghosts(&mut self) {
+        while self.t1.len() + self.b1.len() > self.capacity && self.b1.pop_back().is_some() {}
+        while self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() > 2 * self.capacity
+            && (self.b2.pop_back().is_some() || self.b1.pop_back().is_some()) {}
+    }
+}
+
+impl EvictionPolicy for ArcPolicy {
+    fn insert(&mut self, offset: u64) {
+        let mut in_b2 = false;
+        if self.b1.contains(offset) {
+            // Recently used nodes were evicted too early, grow t1
+            let delta = (self.b2.len() / self.b1.len()).max(1);
+            self.p = (self.p + delta).min(self.capacity);
+            self.b1.remove(offset);
+            self.t2.push_front(offset);
+        } else if self.b2.contains(offset) {
+            // Frequently used nodes were evicted too early, shrink t1
+            let delta = (self.b1.len() / self.b2.len()).max(1);
+            self.p = self.p.saturating_sub(delta);
+            self.b2.remove(offset);
+            self.t2.push_front(offset);
+            in_b2 = true;
+        } else {
+            // Keep at most capacity offsets between t1 and b1, and twice capacity altogether
+            if self.t1.len() + self.b1.len() >= self.capacity {
+                self.b1.pop_back();
+            } else if self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() >= 2 * self.capacity {
+                self.b2.pop_back();
+            }
+            self.t1.push_front(offset);
+        }
+        self.incoming = Some((offset, in_b2));
+    }
+
+    fn access(&mut self, offset: u64) {
+        if self.t1.remove(offset) {
+            self.t2.push_front(offset);
+        } else {
+            self.t2.move_to_front(offset);
+        }
+    }
+
+    /// Evict from t1 if it's over its target size, otherwise from t2, remembering the victim in its ghost list
+    fn evict(&mut self, pinned: &dyn Fn(u64) -> bool) -> Option<u64> {
+        let (incoming, in_b2) = match self.incoming.take() {
+            Some((offset, in_b2)) => (self.t1.contains(offset), in_b2),
+            None => (false, false),
+        };
+        let t1_len = self.t1.len() - usize::from(incoming);
+        let victim = if t1_len > 0 && (t1_len > self.p || (in_b2 && t1_len == self.p)) {
+            Self::evict_from(&mut self.t1, &mut self.b1, pinned)
+                .or_else(|| Self::evict_from(&mut self.t2, &mut self.b2, pinned))
+        } else {
+            Self::evict_from(&mut self.t2, &mut self.b2, pinned)
+                .or_else(|| Self::evict_from(&mut self.t1, &mut self.b1, pinned))
+        };
+        self.trim_ghosts();
+        victim
+    }
+
+    fn remove(&mut self, offset: u64) {
+        for list in [&mut self.t1, &mut self.t2, &mut self.b1, &mut self.b2] {
+            list.remove(offset);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a trace of offsets through policy with room for capacity nodes, returning how many were hits.
+    /// The node being added is pinned while room is made for it.
+    fn hits(policy: &mut dyn EvictionPolicy, capacity: usize, trace: &[u64]) -> usize {
+        let mut cached = std::collections::HashSet::new();
+        let mut hits = 0;
+        for &offset in trace {
+            if cached.contains(&offset) {
+                policy.access(offset);
+                hits += 1;
+                continue
+            }
+            policy.insert(offset);
+            cached.insert(offset);
+            if cached.len() > capacity {
+                let evicted = policy.evict(&|victim| victim == offset).unwrap();
+                assert!(cached.remove(&evicted), "Evicted {evicted} which isn't cached");
+            }
+        }
+        hits
+    }
+
+    fn unpinned(_: u64) -> bool {
+        false
+    }
+
+    #[test]
+    fn test_lru_policy() {
+        let mut policy = LruPolicy::new();
+        for offset in [1, 2, 3] {
+            policy.insert(offset);
+        }
+        policy.access(1);
+        assert_eq!(Some(2), policy.evict(&unpinned));
+        policy.remove(3);
+        policy.insert(4);
+        policy.insert(5);
+        // 1 is the least recently used, but pinned
+        assert_eq!(Some(4), policy.evict(&|offset| offset == 1));
+        assert_eq!(Some(1), policy.evict(&unpinned));
+        assert_eq!(None, policy.evict(&|_| true));
+    }
+
+    #[test]
+    fn test_clock_policy() {
+        let mut policy = ClockPolicy::new();
+        for offset in [1, 2, 3] {
+            policy.insert(offset);
+        }
+        // 1 and 2 get a second chance, 3 doesn't
+        policy.access(1);
+        policy.access(2);
+        assert_eq!(Some(3), policy.evict(&unpinned));
+        // The sweep cleared 1 and 2, and with 2 pinned the hand carries on round to 1
+        assert_eq!(Some(1), policy.evict(&|offset| offset == 2));
+        policy.insert(4);
+        policy.remove(2);
+        assert_eq!(Some(4), policy.evict(&unpinned));
+        assert_eq!(None, policy.evict(&unpinned));
+    }
+
+    #[test]
+    fn test_arc_policy_adapts() {
+        let mut policy = ArcPolicy::new(2);
+        policy.insert(1);
+        policy.insert(2);
+        // 1 is used twice, moving it to t2, so making room for 3 evicts 2 from t1
+        policy.access(1);
+        policy.insert(3);
+        assert_eq!(Some(2), policy.evict(&|offset| offset == 3));
+        assert!(policy.b1.contains(2));
+        // A miss on 2 while it's a ghost grows t1's target to hold 3, so 1 is evicted from t2 instead,
+        // and 2 comes back as frequently used
+        policy.insert(2);
+        assert_eq!(1, policy.p);
+        assert_eq!(Some(1), policy.evict(&|offset| offset == 2));
+        assert!(policy.t1.contains(3) && policy.t2.contains(2) && policy.b2.contains(1));
+        // With 2 pinned it falls back to evicting from t1
+        policy.insert(4);
+        assert_eq!(Some(3), policy.evict(&|offset| offset == 2 || offset == 4));
+        policy.remove(1);
+        assert!(!policy.b2.contains(1));
+    }
+
+    /// Ghosts are bounded by the capacity even when pinned nodes keep more nodes than that in the pool,
+    /// as in a pool without a cache.
+    #[test]
+    fn test_arc_policy_bounds_ghosts() {
+        for capacity in [0, 4] {
+            let mut policy = ArcPolicy::new(capacity);
+            // Three nodes in use at a time, the one being added and the two before it
+            for offset in 0..10_000 {
+                policy.insert(offset);
+                if offset >= 3 {
+                    assert!(policy.evict(&|victim| victim + 3 > offset).is_some());
+                }
+                assert!(policy.t1.len() + policy.b1.len() <= capacity.max(policy.t1.len()));
+                assert!(policy.b1.len() + policy.b2.len() <= 2 * capacity);
+            }
+        }
+    }
+
+    /// An eviction that isn't making room for a node this policy just added, as when the pool evicts a leaf
+    /// for a new internal node, counts every node in t1.
+    #[test]
+    fn test_arc_policy_forgets_incoming() {
+        let mut policy = ArcPolicy::new(3);
+        for offset in [1, 4] {
+            policy.insert(offset);
+            policy.access(offset);
+        }
+        policy.insert(2);
+        // Room for 2 comes out of t2, as t1 only holds 2 itself
+        assert_eq!(Some(1), policy.evict(&|offset| offset == 2));
+        // Later t1 holding 2 is over its target size of 0
+        assert_eq!(Some(2), policy.evict(&|_| false));
+        assert_eq!((0, 1), (policy.t1.len(), policy.t2.len()));
+    }
+
+    /// A hot set reused between scans stays cached with CLOCK and ARC, while LRU lets the scans flush it.
+    #[test]
+    fn test_policies_keep_hot_nodes() {
+        let capacity = 10;
+        let mut trace = Vec::new();
+        for round in 0..50 {
+            for _ in 0..2 {
+                trace.extend(0..5);
+            }
+            trace.extend((0..8).map(|i| 1000 + round * 8 + i));
+        }
+        let lru = hits(&mut LruPolicy::new(), capacity, &trace);
+        let clock = hits(&mut ClockPolicy::new(), capacity, &trace);
+        let arc = hits(&mut ArcPolicy::new(capacity), capacity, &trace);
+        assert!(clock > lru, "CLOCK {clock} hits, LRU {lru} hits");
+        assert!(arc > lru, "ARC {arc} hits, LRU {lru} hits");
+    }
+
+    #[test]
+    fn test_policies_never_exceed_capacity() {
+        use rand::Rng;
+        let mut rng = rand::thread_rng();
+        let trace: Vec<u64> = (0..5000).map(|_| rng.gen_range(0..50)).collect();
+        for kind in [CachePolicy::Lru, CachePolicy::Clock, CachePolicy::Arc] {
+            hits(kind.build(8).as_mut(), 8, &trace);
+        }
+    }
+
+    #[test]
+    fn test_cache_policy_names() {
+        for kind in [CachePolicy::Lru, CachePolicy::Clock, CachePolicy::Arc] {
+            assert_eq!(kind, kind.to_string().parse().unwrap());
+        }
+        assert_eq!(Ok(CachePolicy::Arc), "ARC".parse());
+        assert!("fifo".parse::<CachePolicy>().is_err());
+    }
+}
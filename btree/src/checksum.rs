@@ -0,0 +1,110 @@
+/// Reversed CRC32C (Castagnoli) polynomial, the CRC used by iSCSI, ext4 and SSE4.2's crc32 instruction.
+const POLYNOMIAL: u32 = 0x82f6_3b78;
+
+/// Lookup tables for checksumming 8 bytes at a time. TABLES[0] is the usual byte at a time table,
+/// TABLES[n] gives the CRC of a byte followed by n zero bytes.
+const TABLES: [[u32; 256]; 8] = {
+    let mut tables = [[0u32; 256]; 8];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        tables[0][i] = crc;
+        i += 1;
+    }
+    let mut n = 1;
+    while n < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let previous = tables[n - 1][i];
+            tables[n][i] = (previous >> 8) ^ tables[0][(previous & 0xff) as usize];
+            i += 1;
+        }
+        n += 1;
+    }
+    tables
+};
+
+/// CRC32C checksum of bytes, with the SSE4.2 crc32 instruction where the CPU has it.
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    #[cfg(target_arch = "x86_64")]
+    if std::is_x86_feature_detected!("sse4.2") {
+        // Safe as the CPU has been checked for SSE4.2
+        return unsafe { crc32c_sse42(bytes) };
+    }
+    crc32c_table(bytes)
+}
+
+/// CRC32C checksum of bytes 8 at a time with the crc32 instruction.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.2")]
+unsafe fn crc32c_sse42(bytes: &[u8]) -> u32 {
+    use std::arch::x86_64::{_mm_crc32_u64, _mm_crc32_u8};
+    let mut crc = !0u64;
+    let mut chunks = bytes.chunks_exact(8);
+    for chunk in &mut chunks {
+        crc = _mm_crc32_u64(crc, u64::from_le_bytes(chunk.try_into().unwrap()));
+    }
+    let mut crc = crc as u32;
+    for &byte in chunks.remainder() {
+        crc = _mm_crc32_u8(crc, byte);
+    }
+    !crc
+}
+
+/// CRC32C checksum of bytes 8 at a time with lookup tables.
+fn crc32c_table(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    let mut chunks = bytes.chunks_exact(8);
+    for chunk in &mut chunks {
+        let low = crc ^ u32::from_le_bytes(chunk[..4].try_into().unwrap());
+        let high = u32::from_le_bytes(chunk[4..].try_into().unwrap());
+        crc = TABLES[7][(low & 0xff) as usize]
+            ^ TABLES[6][((low >> 8) & 0xff) as usize]
+            ^ TABLES[5][((low >> 16) & 0xff) as usize]
+            ^ TABLES[4][(low >> 24) as usize]
+            ^ TABLES[3][(high & 0xff) as usize]
+            ^ TABLES[2][((high >> 8) & 0xff) as usize]
+            ^ TABLES[1][((high >> 16) & 0xff) as usize]
+            ^ TABLES[0][(high >> 24) as usize];
+    }
+    for &byte in chunks.remainder() {
+        crc = TABLES[0][((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CRC32C a byte at a time, to check the 8 byte path against
+    fn crc32c_bytewise(bytes: &[u8]) -> u32 {
+        !bytes.iter().fold(!0u32, |crc, &byte| TABLES[0][((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8))
+    }
+
+    #[test]
+    fn test_crc32c_check_values() {
+        assert_eq!(0, crc32c(b""));
+        assert_eq!(0xe306_9283, crc32c(b"123456789"));
+        assert_eq!(0x8a91_36aa, crc32c(&[0u8; 32]));
+        assert_eq!(0x62a8_ab43, crc32c(&[0xffu8; 32]));
+    }
+
+    #[test]
+    fn test_crc32c_any_length() {
+        let bytes: Vec<u8> = (0..1000u32).map(|i| (i * 31 % 251) as u8).collect();
+        for len in [0, 1, 7, 8, 9, 15, 16, 17, 100, 999, 1000] {
+            assert_eq!(crc32c_bytewise(&bytes[..len]), crc32c(&bytes[..len]));
+            assert_eq!(crc32c_bytewise(&bytes[..len]), crc32c_table(&bytes[..len]));
+        }
+        // A single flipped bit changes the checksum
+        let mut flipped = bytes.clone();
+        flipped[500] ^= 0x10;
+        assert_ne!(crc32c(&bytes), crc32c(&flipped));
+    }
+}
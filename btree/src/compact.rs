@@ -0,0 +1,308 @@
+use std::fs::{File, rename};
+use std::ops::Range;
+use std::path::Path;
+use crate::btree_node::{Node, TreeObject};
+use crate::encoding::{Key, Value};
+use crate::error::{Error, Result};
+use crate::options::BTreeOptions;
+use crate::pager::{self, Metadata, Pager};
+use crate::{BTree, Stats};
+
+/// Node of the compacted tree before it's filled in.
+struct Plan {
+    /// Keys the node holds, one less than its children if it isn't a leaf
+    number_of_keys: u64,
+    /// Indexes of the node's children in the plan
+    children: Range<usize>,
+}
+
+/// Rewrite the BTree file file_name with its nodes packed as full as they can be and laid out breadth first,
+/// the root first and then each level in key order, so searches read the file front to back.
+/// Nodes are written in split order as a BTree grows, which scatters the nodes of each level through the file.
+/// The keys are read in order and the new tree is built bottom up in a new file, which then replaces the old
+/// one, leaving no free pages. degree picks the new tree's degree, 0 the largest whose nodes fit in one page,
+/// and None keeps the file's degree. The page size and sequence length are kept. A degree below 2 returns
+/// InvalidDegree, leaving the file as it was.
+/// Returns a summary of the compacted BTree.
+pub fn compact<K: Key, V: Value>(file_name: &str, degree: Option<u32>) -> Result<Stats> {
+    let mut btree: BTree<K, V> = BTree::open(file_name, BTreeOptions::default())?;
+    let page_size = btree.get_page_size();
+    let entry_len = (K::encoded_len() + V::encoded_len()) as u32;
+    let degree = match degree {
+        None => btree.get_degree(),
+        Some(0) => pager::optimal_degree(page_size, entry_len),
+        Some(degree) => degree,
+    };
+    // Checked before anything is written, the compacted file replaces the only copy of the tree
+    if degree < 2 {
+        return Err(Error::InvalidDegree(degree));
+    }
+    let number_of_keys = btree.get_size();
+    let (plan, height) = plan_tree(number_of_keys, degree);
+
+    let compacted_file = format!("{file_name}.compact");
+    let mut pager = Pager::new(&compacted_file, degree, page_size)?;
+    pager.recreate_file(&mut Node::<K, V>::new())?;
+    let first_offset = page_size as u64;
+    let slot_size = pager.slot_size::<K, V>() as u64;
+    let offset = |index: usize| first_offset + index as u64 * slot_size;
+    let mut keys = Keys { iter: btree.iter(), taken: 0, expected: number_of_keys };
+    fill_node(&plan, 0, &offset, &mut keys, &mut pager)?;
+    keys.finish()?;
+    let metadata = Metadata {
+        page_size,
+        degree,
+        sequence_length: btree.get_sequence_length(),
+        key_width: K::encoded_len() as u32,
+        value_width: V::encoded_len() as u32,
+        root_offset: offset(0),
+        number_of_nodes: plan.len() as u64,
+        number_of_keys,
+        height,
+        file_cursor: offset(plan.len()),
+        free_list: 0,
+        free_pages: 0,
+    };
+    pager.write_metadata(&metadata)?;
+    pager.sync()?;
+    drop(pager);
+    // The old file stays locked until it's replaced, so nothing else can write to it in between, but its log
+    // has to go first, as something could open the new file and log to the same path once it's in place
+    btree.close_wal()?;
+    rename(&compacted_file, file_name)?;
+    drop(btree);
+    sync_parent_dir(file_name)?;
+    Ok(Stats {
+        degree,
+        number_of_keys,
+        number_of_nodes: metadata.number_of_nodes,
+        height: metadata.height,
+        file_size: metadata.file_cursor,
+        free_pages: 0,
+        cache_capacity: None,
+        cache: None,
+    })
+}
+
+/// Flush the directory holding file_name to disk, so a file renamed to file_name stays renamed after a crash.
+fn sync_parent_dir(file_name: &str) -> Result<()> {
+    let dir = Path::new(file_name).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Split total between parts as evenly as it goes, the first ones getting one more than the rest.
+fn share(total: u64, parts: u64, part: u64) -> u64 {
+    total / parts + u64::from(part < total % parts)
+}
+
+/// Shape of a BTree holding number_of_keys keys with as few nodes as it can, in breadth first order,
+/// and its height.
+/// Levels are counted from the leaves up, each with as few nodes as hold the one below, and the keys,
+/// then children, are shared out evenly along each level. So the leaves are as full as they can be
+/// and no node below the root has fewer than degree - 1 keys.
+fn plan_tree(number_of_keys: u64, degree: u32) -> (Vec<Plan>, u32) {
+    let max_children = 2 * degree as u64;
+    // n keys in l leaves leave l - 1 keys for the nodes above them, each leaf holding at most 2t - 1
+    let mut levels = vec![(number_of_keys + 1).div_ceil(max_children)];
+    while *levels.last().unwrap() > 1 {
+        levels.push(levels.last().unwrap().div_ceil(max_children));
+    }
+    levels.reverse();
+    let mut plan = Vec::new();
+    for pair in levels.windows(2) {
+        let (nodes, children) = (pair[0], pair[1]);
+        // The level below starts straight after this one
+        let mut start = plan.len() + nodes as usize;
+        for node in 0..nodes {
+            let node_children = share(children, nodes, node) as usize;
+            plan.push(Plan { number_of_keys: node_children as u64 - 1, children: start..start + node_children });
+            start += node_children;
+        }
+    }
+    let leaves = *levels.last().unwrap();
+    let leaf_keys = number_of_keys - (leaves - 1);
+    plan.extend((0..leaves).map(|leaf| Plan { number_of_keys: share(leaf_keys, leaves, leaf), children: 0..0 }));
+    (plan, levels.len() as u32 - 1)
+}
+
+/// Keys for the compacted tree, read in order from the old one and counted,
+/// so a file that holds a different number of keys than its header says is reported as Corrupt.
+struct Keys<I> {
+    iter: I,
+    taken: u64,
+    expected: u64,
+}
+
+impl<K: Key, V: Value, I: Iterator<Item = Result<TreeObject<K, V>>>> Keys<I> {
+    fn next_key(&mut self) -> Result<TreeObject<K, V>> {
+        let key = self.iter.next().unwrap_or(Err(Error::Corrupt { offset: 0, expected: self.expected, found: self.taken }))?;
+        self.taken += 1;
+        Ok(key)
+    }
+
+    /// Every key has been taken, none are left over.
+    fn finish(mut self) -> Result<()> {
+        match self.iter.next() {
+            None => Ok(()),
+            Some(key) => {
+                key?;
+                Err(Error::Corrupt { offset: 0, expected: self.expected, found: self.taken + 1 })
+            },
+        }
+    }
+}
+
+/// Fill in the node at index in the plan, and the subtree under it, with keys taken in order,
+/// then write it at its offset in the breadth first layout.
+fn fill_node<K: Key, V: Value, I: Iterator<Item = Result<TreeObject<K, V>>>>(
+    plan: &[Plan], index: usize, offset: &impl Fn(usize) -> u64, keys: &mut Keys<I>, pager: &mut Pager,
+) -> Result<()> {
+    let mut node: Node<K, V> = Node::new();
+    node.offset = offset(index);
+    let children = plan[index].children.clone();
+    node.is_leaf = children.is_empty();
+    if node.is_leaf {
+        for _ in 0..plan[index].number_of_keys {
+            node.keys.push(keys.next_key()?);
+        }
+    } else {
+        for child in children.clone() {
+            fill_node(plan, child, offset, keys, pager)?;
+            node.children_ptrs.push(offset(child));
+            if child + 1 < children.end {
+                node.keys.push(keys.next_key()?);
+            }
+        }
+    }
+    node.number_of_keys = node.keys.len() as u32;
+    pager.write(&node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Helper function, delete files for test cleanup
+    fn delete_file(file: &str){
+        std::fs::remove_file(file).ok();
+    }
+
+    /// BTree with keys 0 to 2000 inserted and every third one deleted, leaving its nodes part full
+    /// and free pages in the file. Returns the keys and values it holds.
+    fn fragmented_btree(file_name: &str) -> Vec<(u64, u64)> {
+        delete_file(file_name);
+        let mut b: BTree = BTree::new(2, file_name, true, 10, true).unwrap();
+        for i in 0..2000 {
+            b.insert(i).unwrap();
+        }
+        for i in (0..2000).step_by(3) {
+            b.delete(i).unwrap();
+        }
+        for i in 0..100 {
+            b.insert(i).unwrap();
+        }
+        let keys = b.iter().map(|obj| obj.map(|obj| (obj.key, obj.value)).unwrap()).collect();
+        assert!(b.close().unwrap().free_pages > 0);
+        keys
+    }
+
+    /// Read every node of a compacted file front to back, checking they're laid out breadth first with
+    /// no gaps, each non root node holds at least degree - 1 keys and the leaves are all at one depth.
+    fn check_layout(file_name: &str, stats: &Stats) {
+        let page_size = BTree::<u64, u64>::open(file_name, BTreeOptions::default()).unwrap().get_page_size();
+        let mut pager = Pager::new(file_name, stats.degree, page_size).unwrap();
+        let slot_size = pager.slot_size::<u64, u64>() as u64;
+        let offset = |index: u64| page_size as u64 + index * slot_size;
+        assert_eq!(offset(0), pager.read_metadata().unwrap().root_offset);
+        let mut depths = vec![0];
+        for index in 0..stats.number_of_nodes {
+            let node: Node = pager.read(offset(index)).unwrap();
+            let depth = depths[index as usize];
+            if index > 0 {
+                assert!(node.number_of_keys >= stats.degree - 1, "Node {index} is under full");
+            }
+            if node.is_leaf {
+                assert_eq!(stats.height, depth, "Leaf {index} isn't at the bottom");
+            }
+            // Children come straight after the nodes before them in breadth first order
+            for &child in &node.children_ptrs {
+                assert_eq!(offset(depths.len() as u64), child);
+                depths.push(depth + 1);
+            }
+        }
+        assert_eq!(stats.number_of_nodes as usize, depths.len());
+        assert_eq!(offset(stats.number_of_nodes), stats.file_size);
+        assert_eq!(stats.file_size, std::fs::metadata(file_name).unwrap().len());
+    }
+
+    #[test]
+    fn test_compact() {
+        let file_name = "test_compact.tmp";
+        let keys = fragmented_btree(file_name);
+        let file_size = std::fs::metadata(file_name).unwrap().len();
+        let stats = compact::<u64, u64>(file_name, None).unwrap();
+        assert_eq!((2, keys.len() as u64, 0), (stats.degree, stats.number_of_keys, stats.free_pages));
+        assert!(stats.file_size < file_size);
+        // 342 full leaves holding 1026 of the 1367 keys, and 117 nodes above them
+        assert_eq!((1367, 459, 5), (keys.len(), stats.number_of_nodes, stats.height));
+        check_layout(file_name, &stats);
+        assert!(!std::path::Path::new(&format!("{file_name}.compact")).exists());
+        let mut b: BTree = BTree::new(2, file_name, true, 10, false).unwrap();
+        assert_eq!(stats.height, b.get_height());
+        let compacted: Vec<(u64, u64)> = b.iter().map(|obj| obj.map(|obj| (obj.key, obj.value)).unwrap()).collect();
+        assert_eq!(keys, compacted);
+        // The compacted BTree can still be added to
+        for i in 0..2000 {
+            b.insert(i).unwrap();
+        }
+        assert_eq!((0..2000).collect::<Vec<u64>>(), b.get_sorted_key_array().unwrap());
+        delete_file(file_name);
+    }
+
+    #[test]
+    fn test_compact_degree() {
+        let file_name = "test_compact_degree.tmp";
+        let keys = fragmented_btree(file_name);
+        let page_size = BTree::<u64, u64>::open(file_name, BTreeOptions::default()).unwrap().get_page_size();
+        for (degree, expected) in [(Some(5), 5), (Some(0), pager::optimal_degree(page_size, 16)), (Some(2), 2)] {
+            let stats = compact::<u64, u64>(file_name, degree).unwrap();
+            assert_eq!(expected, stats.degree);
+            check_layout(file_name, &stats);
+            let mut b: BTree = BTree::new(0, file_name, true, 10, false).unwrap();
+            assert_eq!(expected, b.get_degree());
+            let compacted: Vec<(u64, u64)> = b.iter().map(|obj| obj.map(|obj| (obj.key, obj.value)).unwrap()).collect();
+            assert_eq!(keys, compacted);
+        }
+        delete_file(file_name);
+    }
+
+    /// A degree too small for a BTree is refused before the file is touched.
+    #[test]
+    fn test_compact_invalid_degree() {
+        let file_name = "test_compact_invalid_degree.tmp";
+        fragmented_btree(file_name);
+        let original = std::fs::read(file_name).unwrap();
+        assert!(matches!(compact::<u64, u64>(file_name, Some(1)), Err(Error::InvalidDegree(1))));
+        assert_eq!(original, std::fs::read(file_name).unwrap());
+        assert!(!std::path::Path::new(&format!("{file_name}.compact")).exists());
+        assert!(BTree::<u64, u64>::new(2, file_name, true, 10, false).is_ok());
+        delete_file(file_name);
+    }
+
+    #[test]
+    fn test_compact_empty() {
+        let file_name = "test_compact_empty.tmp";
+        delete_file(file_name);
+        let b: BTree = BTree::new(3, file_name, true, 10, true).unwrap();
+        drop(b);
+        let stats = compact::<u64, u64>(file_name, None).unwrap();
+        assert_eq!((0, 1, 0), (stats.number_of_keys, stats.number_of_nodes, stats.height));
+        check_layout(file_name, &stats);
+        let mut b: BTree = BTree::new(3, file_name, true, 10, false).unwrap();
+        assert!(b.iter().next().is_none());
+        b.insert(42).unwrap();
+        assert_eq!(vec![42], b.get_sorted_key_array().unwrap());
+        delete_file(file_name);
+    }
+}
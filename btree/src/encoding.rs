@@ -0,0 +1,97 @@
+/// Fixed size type that can be written to and read back from a BTree file.
+/// Every value of a type has to encode to exactly encoded_len bytes, so nodes have a fixed size on disk.
+pub trait Encodable: Sized {
+    /// Number of bytes every encoded value takes up
+    fn encoded_len() -> usize;
+
+    /// Write value into buf, which is exactly encoded_len bytes long
+    fn encode(&self, buf: &mut [u8]);
+
+    /// Read value back out of buf, which is exactly encoded_len bytes long
+    fn decode(buf: &[u8]) -> Self;
+}
+
+/// Types that can be used as BTree keys, keys are ordered and copied in and out of nodes.
+pub trait Key: Encodable + Ord + Copy {}
+
+impl<T: Encodable + Ord + Copy> Key for T {}
+
+/// Types that can be stored as BTree values alongside a key.
+pub trait Value: Encodable + Copy {}
+
+impl<T: Encodable + Copy> Value for T {}
+
+/// Implement Encodable for integer primitives, stored big endian like the rest of the file.
+macro_rules! encodable_integer {
+    ($($t:ty),*) => {
+        $(
+            impl Encodable for $t {
+                fn encoded_len() -> usize {
+                    std::mem::size_of::<$t>()
+                }
+
+                fn encode(&self, buf: &mut [u8]) {
+                    buf.copy_from_slice(&self.to_be_bytes());
+                }
+
+                fn decode(buf: &[u8]) -> Self {
+                    <$t>::from_be_bytes(buf.try_into().expect("Buffer doesn't match encoded length"))
+                }
+            }
+        )*
+    };
+}
+
+encodable_integer!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+/// Fixed length arrays are stored as each element one after another, for example per sample counts.
+impl<T: Encodable, const N: usize> Encodable for [T; N] {
+    fn encoded_len() -> usize {
+        T::encoded_len() * N
+    }
+
+    fn encode(&self, buf: &mut [u8]) {
+        for (item, chunk) in self.iter().zip(buf.chunks_exact_mut(T::encoded_len())) {
+            item.encode(chunk);
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        std::array::from_fn(|i| T::decode(&buf[i * T::encoded_len()..(i + 1) * T::encoded_len()]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode value then decode it, checking the buffer used matches encoded_len.
+    fn round_trip<T: Encodable>(value: &T) -> T {
+        let mut buf = vec![0u8; T::encoded_len()];
+        value.encode(&mut buf);
+        T::decode(&buf)
+    }
+
+    #[test]
+    fn test_encode_integers() {
+        assert_eq!(8, u64::encoded_len());
+        assert_eq!(16, u128::encoded_len());
+        assert_eq!(u64::MAX - 7, round_trip(&(u64::MAX - 7)));
+        assert_eq!(u128::MAX / 3, round_trip(&(u128::MAX / 3)));
+        assert_eq!(-42i32, round_trip(&-42i32));
+    }
+
+    #[test]
+    fn test_encode_integers_big_endian() {
+        let mut buf = [0u8; 4];
+        0x01020304u32.encode(&mut buf);
+        assert_eq!([1, 2, 3, 4], buf);
+    }
+
+    #[test]
+    fn test_encode_array() {
+        let counts: [u32; 4] = [1, 20, 300, 4000];
+        assert_eq!(16, <[u32; 4]>::encoded_len());
+        assert_eq!(counts, round_trip(&counts));
+    }
+}
@@ -0,0 +1,94 @@
+use std::fmt;
+
+/// Errors from reading or writing a BTree file.
+/// A BTree that returned an error part way through an insert or delete shouldn't be written to again,
+/// the nodes written before the error may not agree with the ones after it.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from or writing to the file failed, including files that end part way through a node.
+    Io(std::io::Error),
+    /// The node at offset holds found where expected was expected, the file is corrupt or not a BTree file.
+    Corrupt { offset: u64, expected: u64, found: u64 },
+    /// The node at offset, or the header at offset 0, doesn't match the CRC32C checksum stored with it.
+    /// It has changed since it was written, from bit rot or something else writing to the file.
+    ChecksumMismatch { offset: u64, expected: u32, found: u32 },
+    /// The file was created with a different degree than the one it was opened with.
+    DegreeMismatch { expected: u32, found: u32 },
+    /// The file doesn't start with a BTree header.
+    NotBTreeFile,
+    /// The file was written with a different version of the file layout.
+    VersionMismatch { expected: u32, found: u32 },
+    /// The file was written in the original layout, from before the header was versioned, and has to be
+    /// brought up to date with migrate::migrate before it can be opened.
+    NeedsMigration,
+    /// The file stores keys and values of different widths, (key, value) in bytes, than the BTree it was opened as.
+    EncodingMismatch { expected: (u32, u32), found: (u32, u32) },
+    /// The page size is too small to hold the file's header.
+    InvalidPageSize(u32),
+    /// The degree is too small for a BTree, which needs a degree of at least 2.
+    InvalidDegree(u32),
+    /// The file is already open as another BTree, in this process or another one.
+    /// Only one BTree can have a file open at a time, as its write-ahead log belongs to that BTree,
+    /// unless every BTree that has it open is read only.
+    FileInUse,
+    /// The BTree was opened read only, and can't be changed or truncated.
+    ReadOnly,
+    /// The file was opened read only with a batch left committed in its write-ahead log, by a BTree that
+    /// didn't close, which couldn't be copied to the file as the file or its log can't be written.
+    RecoveryNeedsWrite,
+}
+
+/// Result type returned by BTree and its file operations.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "BTree file couldn't be read or written: {err}"),
+            Error::Corrupt { offset, expected, found } => {
+                write!(f, "BTree file is corrupt, node at offset {offset} expected {expected} but found {found}")
+            },
+            Error::ChecksumMismatch { offset: 0, expected, found } => {
+                write!(f, "BTree file is corrupt, header has checksum {found:#010x} but {expected:#010x} was stored")
+            },
+            Error::ChecksumMismatch { offset, expected, found } => {
+                write!(f, "BTree file is corrupt, node at offset {offset} has checksum {found:#010x} but {expected:#010x} was stored")
+            },
+            Error::DegreeMismatch { expected, found } => {
+                write!(f, "BTree file has degree {found}, not the expected degree {expected}")
+            },
+            Error::NotBTreeFile => write!(f, "Not a BTree file"),
+            Error::VersionMismatch { expected, found } => {
+                write!(f, "BTree file is version {found}, only version {expected} can be read")
+            },
+            Error::NeedsMigration => {
+                write!(f, "BTree file is in the original layout from before the file format was versioned, it has to be migrated first")
+            },
+            Error::EncodingMismatch { expected, found } => {
+                write!(f, "BTree file stores {} byte keys and {} byte values, not the expected {} and {}", found.0, found.1, expected.0, expected.1)
+            },
+            Error::InvalidPageSize(page_size) => write!(f, "Page size {page_size} is too small to hold the BTree header"),
+            Error::InvalidDegree(degree) => write!(f, "Degree {degree} is too small, a BTree needs degree 2 or more"),
+            Error::FileInUse => write!(f, "BTree file is already open"),
+            Error::ReadOnly => write!(f, "BTree file was opened read only"),
+            Error::RecoveryNeedsWrite => {
+                write!(f, "BTree file wasn't closed, its write-ahead log has to be recovered by opening it with write access")
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
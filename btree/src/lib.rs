@@ -1,143 +1,207 @@
-mod btree_cache;
+mod buffer_pool;
+mod checksum;
 mod pager;
+mod stats;
+mod wal;
+pub mod btree_iter;
 pub mod btree_node;
+pub mod cache_policy;
+pub mod compact;
+pub mod encoding;
+pub mod error;
+pub mod migrate;
+pub mod options;
 
 use std::cell::{RefCell, Ref};
+use std::ops::RangeBounds;
 use std::rc::Rc;
-use crate::pager::Pager;
+use crate::pager::{Pager, Metadata};
 use crate::btree_node::*;
-use crate::btree_cache::BTreeCache;
+use crate::buffer_pool::BufferPool;
+use crate::btree_iter::Iter;
+use crate::encoding::{Key, Value};
+pub use crate::error::{Error, Result};
+pub use crate::migrate::migrate;
+pub use crate::compact::compact;
+pub use crate::options::BTreeOptions;
+pub use crate::buffer_pool::CacheStats;
+pub use crate::stats::Stats;
+pub use crate::cache_policy::{CachePolicy, EvictionPolicy};
 
 /// Btree Struct, containing meta data about tree,
 /// along with objects for caching or writing nodes to disk.
-pub struct BTree {
+/// Generic over fixed size keys and values, the default parameters are the k-mer counter
+/// storing 2 bit encoded gene sequences with their frequencies.
+pub struct BTree<K: Key = u64, V: Value = u64> {
     degree: u32,
-    number_of_nodes: u32,
-    number_of_keys: u32,
+    number_of_nodes: u64,
+    number_of_keys: u64,
     height: u32,
+    sequence_length: u32,
     pager: Pager,
-    cache: Option<BTreeCache>,
-    root_node: Rc<RefCell<Node>>,
+    /// Every node in memory, holding recently used nodes as well when the cache is used
+    pool: BufferPool<K, V>,
+    use_cache: bool,
+    /// Handle to the root, pinning it in the pool so it's only written to file when flushed
+    root_node: Rc<RefCell<Node<K, V>>>,
+    /// Counts, height, root offset or sequence length have changed since the header was last written
+    metadata_dirty: bool,
+    /// Opened with BTreeOptions::read_only, so anything that would change the BTree returns ReadOnly
+    read_only: bool,
 }
 
-impl BTree {
-    /// Constructor to build Btree
-    pub fn new(mut degree: u32, file_name: &str, use_cache: bool, cache_size: u32, truncate_file: bool) -> BTree {
-        // If degree is 0, set degree to most optimal for 4096 bytes
-        if degree == 0 {
-            degree = 102;
+impl<K: Key, V: Value> BTree<K, V> {
+    /// Constructor to build Btree, with the default page size.
+    /// When opening an existing file without truncating it, the degree, counts, height and sequence length
+    /// are restored from the file's metadata. A degree of 0 accepts whichever degree the file was created with,
+    /// any other degree has to match it.
+    pub fn new(degree: u32, file_name: &str, use_cache: bool, cache_size: u32, truncate_file: bool) -> Result<BTree<K, V>> {
+        BTree::open(file_name, BTreeOptions { degree, use_cache, cache_size, truncate_file, ..Default::default() })
+    }
+
+    /// Create or open the BTree stored in file_name, see BTreeOptions.
+    pub fn open(file_name: &str, options: BTreeOptions) -> Result<BTree<K, V>> {
+        let BTreeOptions { degree, page_size, use_cache, cache_size, cache_memory, cache_policy, truncate_file, read_only } = options;
+        // If degree is 0, use the largest degree whose nodes fit in a page
+        let entry_len = (K::encoded_len() + V::encoded_len()) as u32;
+        let optimal_degree = if degree == 0 { pager::optimal_degree(page_size, entry_len) } else { degree };
+        if optimal_degree < 2 {
+            return Err(Error::InvalidDegree(optimal_degree));
+        }
+        if read_only && truncate_file {
+            return Err(Error::ReadOnly);
         }
         // Retreave the root node if possible
-        let mut pager = Pager::new(&file_name, degree).unwrap();
+        let mut pager = if read_only {
+            // Finish writing the last batch committed before a crash, before reading anything it changed
+            Pager::open_read_only(file_name, optimal_degree, page_size)?
+        } else {
+            Pager::new(file_name, optimal_degree, page_size)?
+        };
         
         // Create node and recreate pager file if it already exists.
         let mut node = Node::new();
-        if truncate_file {
-            node.offset = pager.recreate_file(file_name, degree, &node);
+        let metadata = if truncate_file {
+            // Lock the file before anything in it, or in its log, is thrown away
+            pager.open_wal(file_name, false)?;
+            pager.recreate_file(&mut node)?;
+            pager.read_metadata()?
         } else {
-            node = pager.read_root().unwrap();
-        }
+            // Finish writing the last batch committed before a crash, before reading anything it changed
+            if !read_only {
+                pager.open_wal(file_name, true)?;
+            }
+            let metadata = pager.read_metadata()?;
+            if degree != 0 && degree != metadata.degree {
+                return Err(Error::DegreeMismatch { expected: degree, found: metadata.degree });
+            }
+            let widths = (K::encoded_len() as u32, V::encoded_len() as u32);
+            if widths != (metadata.key_width, metadata.value_width) {
+                return Err(Error::EncodingMismatch { expected: widths, found: (metadata.key_width, metadata.value_width) });
+            }
+            // Nodes have to be read with the degree and page size the file was written with
+            pager.set_layout(metadata.degree, metadata.page_size);
+            pager.file_cursor = metadata.file_cursor;
+            pager.free_list = metadata.free_list;
+            pager.free_pages = metadata.free_pages;
+            node = pager.read(metadata.root_offset)?;
+            metadata
+        };
         
         // Add node to refcel
         let refcell_node = Rc::new(RefCell::new(node));
 
-        // Create Cache - commented out till I can get Rc working again
-        let cache = if use_cache {
-            // Add root node
-            let mut cache = BTreeCache::new(cache_size);
-            cache.add_object(refcell_node.clone());
-            Some(cache)
-        } else {
-            None
+        // Without a cache the pool only holds the nodes in use
+        let capacity = match (use_cache, cache_memory) {
+            (false, _) => 0,
+            (true, Some(bytes)) => (bytes / buffer_pool::node_memory::<K, V>(metadata.degree, cache_policy)).min(u32::MAX as u64) as u32,
+            (true, None) => cache_size,
         };
+        let mut pool = BufferPool::new(capacity, cache_policy);
+        pool.insert(refcell_node.clone(), false);
 
-        let btree = BTree {
-            degree,
-            number_of_nodes: 1,
-            number_of_keys: 0,
-            height: 0,
+        Ok(BTree {
+            degree: metadata.degree,
+            number_of_nodes: metadata.number_of_nodes,
+            number_of_keys: metadata.number_of_keys,
+            height: metadata.height,
+            sequence_length: metadata.sequence_length,
             pager,
-            cache,
+            pool,
+            use_cache,
             root_node: refcell_node,
-        };
-        btree
+            metadata_dirty: false,
+            read_only,
+        })
+    }
+
+    /// Get the value stored for key, or None if key isn't in the BTree.
+    pub fn get(&mut self, key: K) -> Result<Option<V>> {
+        Ok(self.btree_search_root(key)?.map(|obj| obj.value))
+    }
+
+    /// Check if key is stored in the BTree.
+    pub fn contains(&mut self, key: K) -> Result<bool> {
+        Ok(self.btree_search_root(key)?.is_some())
+    }
+
+    /// Insert key with the value returned by f, f is given the currently stored value if key is
+    /// already in the BTree, and its result replaces the stored value.
+    pub fn upsert_with<F: FnOnce(Option<V>) -> V>(&mut self, key: K, f: F) -> Result<()> {
+        self.btree_insert(key, f)
     }
 
     /// Search btree for key sequence, starting at btree root.
-    pub fn btree_search_root(&mut self, key: TreeObject) -> Option<TreeObject> {
-        let root_node = self.read_root();
+    fn btree_search_root(&mut self, key: K) -> Result<Option<TreeObject<K, V>>> {
+        let root_node = self.root_node.clone();
         self.btree_search(root_node.borrow(), key)
     }
     
-    /// Searches the BTree for the TreeObject given as an argument
-    pub fn btree_search(&mut self, given_root: Ref<Node>, key: TreeObject) -> Option<TreeObject> {
+    /// Searches the BTree for the key given as an argument
+    fn btree_search(&mut self, given_root: Ref<Node<K, V>>, key: K) -> Result<Option<TreeObject<K, V>>> {
         let mut index = 0;
-        while index < given_root.keys.len() && key > *given_root.keys.get(index).unwrap() {
+        while index < given_root.keys.len() && key > given_root.keys.get(index).unwrap().key {
             index += 1;
         }
-        if index < given_root.keys.len() && key == *given_root.keys.get(index).unwrap() {
-            return Some(given_root.keys.get(index).unwrap().clone());
+        if index < given_root.keys.len() && key == given_root.keys.get(index).unwrap().key {
+            Ok(Some(*given_root.keys.get(index).unwrap()))
         } else if given_root.is_leaf() {
-            return None
+            Ok(None)
         } else {
-            let child = self.read(*given_root.children_ptrs.get(index).unwrap());
-            return self.btree_search(child.borrow(), key);
+            let child = self.read(*given_root.children_ptrs.get(index).unwrap())?;
+            self.btree_search(child.borrow(), key)
         }
     }
 
-    // TODO Shouldn't this return child offsets of keys instead of keys, cause big enough btree this would take alot of memory.
-    // TODO Also this might change once we add a cache, well anything with reference counters will change.
-    //// Traverse Btree In Order, returning Vec of sorted keys
-    pub fn btree_in_order_traversal(&mut self, node_offset_option: Option<u32>, sorted_keys: &mut Vec<TreeObject>) {
-        if let Some(node_offset) = node_offset_option {
-            let node = self.read(node_offset);
-            for i in 0..node.borrow().children_ptrs.len() {
-                self.btree_in_order_traversal(node.borrow().children_ptrs.get(i).copied(), sorted_keys);
-                if i < node.borrow().keys.len() {
-                    sorted_keys.push(node.borrow().keys.get(i).copied().expect("Reason TODO"));
-                }
-            }
-            if node.borrow().children_ptrs.len() == 0{
-                for i in 0..node.borrow().keys.len() {
-                    sorted_keys.push(node.borrow().keys.get(i).copied().expect("Reason TODO"));
-                }
-            }
-        }
+    /// Lazy iterator over every TreeObject in key order, nodes are only read as the iterator reaches them.
+    /// An error reading a node is returned in place of the next TreeObject, and ends the iteration.
+    pub fn iter(&mut self) -> Iter<'_, K, V> {
+        self.range(..)
     }
 
-    /// Get sorted Vec of key TreeObject  - method lies, its a vec and vecs can grow so btree_in_order_traversal uses one.
-    pub fn get_sorted_array(&mut self) -> Vec<TreeObject> {
-        let mut sorted_keys: Vec<TreeObject> = Vec::new();
-        // If no offset is found, due to empty file return empty Vec
-        // TODO or return None it might be better.
-        let root_offset = match self.pager.get_root_offset() {
-            Ok(root_offset)  => root_offset,
-            Err(_) => return vec![],
-        };
-        self.btree_in_order_traversal(Some(root_offset), &mut sorted_keys);
-        sorted_keys
+    /// Lazy iterator over the TreeObjects with keys within range, in key order.
+    /// Node keys are used to skip subtrees outside of the range, so only the nodes holding keys
+    /// in range and the paths down to them are read. For k-mer trees a range of encoded keys
+    /// is a lexicographic range of gene sequences of the same length.
+    pub fn range<R: RangeBounds<K>>(&mut self, range: R) -> Iter<'_, K, V> {
+        Iter::new(self, range.start_bound().cloned(), range.end_bound().cloned())
     }
 
+    /// Get sorted Vec of key TreeObject, holds every key in memory so prefer iter for large BTrees.
+    pub fn get_sorted_array(&mut self) -> Result<Vec<TreeObject<K, V>>> {
+        self.iter().collect()
+    }
 
-    /// Get sorted Vec of Key Sequences in their binary form, represented as a u64 value.
-    pub fn get_sorted_key_array(&mut self) -> Vec<u64> {
-        let mut sorted_keys: Vec<TreeObject> = Vec::new();
-        // If no offset is found, due to empty file return empty Vec
-        // TODO or return None it might be better.
-        let root_offset = match self.pager.get_root_offset() {
-            Ok(root_offset)  => root_offset,
-            Err(_) => return vec![],
-        };
-        self.btree_in_order_traversal(Some(root_offset), &mut sorted_keys);
-        sorted_keys.iter().map(| x | x.sequence ).collect()
+    /// Get sorted Vec of Keys, for k-mer trees the sequences in their binary form represented as a u64 value.
+    pub fn get_sorted_key_array(&mut self) -> Result<Vec<K>> {
+        self.iter().map(| x | x.map(| x | x.key )).collect()
     }
 
     /// Splits the tree when the degree of a node gets to size of degree
-    pub fn btree_split_child(&mut self, given_root: Rc<RefCell<Node>>, index: u32) {
-        // let mut borrowed_root = given_root.borrow_mut();
-        let y: Rc<RefCell<Node>> = self.read(*given_root.borrow().children_ptrs.get(index as usize).unwrap());
-        let mut z: Node = Node::new();
-        z.offset = self.pager.file_cursor;
+    fn btree_split_child(&mut self, given_root: Rc<RefCell<Node<K, V>>>, index: u32) -> Result<()> {
+        let y: Rc<RefCell<Node<K, V>>> = self.read(*given_root.borrow().children_ptrs.get(index as usize).unwrap())?;
+        let mut z: Node<K, V> = Node::new();
+        z.offset = self.pager.allocate::<K, V>()?;
         z.is_leaf = y.borrow().is_leaf;
         z.number_of_keys = self.degree - 1;
 
@@ -156,78 +220,86 @@ impl BTree {
         given_root.borrow_mut().number_of_keys = given_root_num_keys + 1;
         given_root.borrow_mut().children_ptrs.insert(index as usize + 1, z.offset );
         given_root.borrow_mut().keys.insert(index as usize, y.borrow_mut().keys.remove(self.degree as usize - 1));
-        self.write(&y);
-        self.write(&Rc::new(RefCell::new(z)));
-        self.write(&given_root);  // TODO This will fail.
+        self.number_of_nodes += 1;
+        self.write(&y)?;
+        self.write(&Rc::new(RefCell::new(z)))?;
+        self.write(&given_root)
     }
 
-    /// Inserts a node into the BTree
-    pub fn btree_insert(&mut self, key: TreeObject){
+    /// Inserts a key into the BTree, splitting the root first when it is full.
+    /// update is called exactly once, with the stored value if the key is already in the BTree.
+    fn btree_insert<F: FnOnce(Option<V>) -> V>(&mut self, key: K, update: F) -> Result<()> {
+        self.check_writable()?;
+        let counts = (self.number_of_nodes, self.number_of_keys);
         if self.root_node.borrow().keys.len() as u32 == self.maximum_keys() {
             self.height += 1;
-            let old_root = self.root_node.replace(Node::new());
-            self.root_node.borrow_mut().is_leaf = false;
-            self.root_node.borrow_mut().number_of_keys = 0;
-            self.root_node.borrow_mut().add_child_ptr(old_root.offset);
-            self.root_node.borrow_mut().offset = self.pager.file_cursor;
-            // Write above to file
-            self.write(&self.root_node.clone());  // This needs to be written to move file cursor, or we move the file cursor some other way.
-            // self.pager.write(&old_root);
-            self.pager.write_metadata(self.root_node.borrow().offset, self.degree);
+            // New root node, the old root stays in the buffer pool under its own offset
+            let mut new_root = Node::new();
+            new_root.is_leaf = false;
+            new_root.number_of_keys = 0;
+            new_root.add_child_ptr(self.root_node.borrow().offset);
+            new_root.offset = self.pager.allocate::<K, V>()?;
+            let old_root = std::mem::replace(&mut self.root_node, Rc::new(RefCell::new(new_root)));
+            // Old root is an ordinary node now, unpinned once the split is done, and evicted or written like any other
+            self.write(&old_root)?;
+            self.write(&self.root_node.clone())?;
             self.number_of_nodes += 1;
-            self.btree_split_child(self.root_node.clone(), 0);
-            self.btree_insert_non_full(self.root_node.clone(), key);
-        } else {        
-            self.btree_insert_non_full(self.root_node.clone(), key);
+            self.btree_split_child(self.root_node.clone(), 0)?;
+        }
+        self.btree_insert_non_full(self.root_node.clone(), key, update)?;
+        // Updating an existing key's value leaves the metadata as is
+        if counts != (self.number_of_nodes, self.number_of_keys) {
+            self.metadata_dirty = true;
         }
+        Ok(())
     }
 
-    /// Inserts an object into the BTree, when the BTree is not full.
-    pub fn btree_insert_non_full(&mut self, given_root: Rc<RefCell<Node>>, key: TreeObject) {
-        let mut index: isize = given_root.borrow().keys.len() as isize;
-        if given_root.borrow().is_leaf() {
-            while index > 0 && key < *given_root.borrow().keys.get(index as usize - 1).unwrap() {
-                index -= 1;
-            }
-            if index > 0 && key == *given_root.borrow().keys.get(index as usize - 1).unwrap() {
-                given_root.borrow_mut().keys.get_mut(index as usize - 1).unwrap().increase_frequency();
-            } else {
-                given_root.borrow_mut().keys.insert(index as usize, key);
-                given_root.borrow_mut().number_of_keys += 1;
-                self.number_of_keys += 1;
-            }
-            self.write(&given_root);
+    /// Inserts a key into the subtree rooted at given_root, when given_root is not full.
+    fn btree_insert_non_full<F: FnOnce(Option<V>) -> V>(&mut self, given_root: Rc<RefCell<Node<K, V>>>, key: K, update: F) -> Result<()> {
+        let mut index = given_root.borrow().keys.len();
+        while index > 0 && key < given_root.borrow().keys[index - 1].key {
+            index -= 1;
+        }
+        if index > 0 && key == given_root.borrow().keys[index - 1].key {
+            self.btree_update_at(&given_root, index - 1, update)
+        } else if given_root.borrow().is_leaf() {
+            given_root.borrow_mut().keys.insert(index, TreeObject::new(key, update(None)));
+            given_root.borrow_mut().number_of_keys += 1;
+            self.number_of_keys += 1;
+            self.write(&given_root)
         } else {
-            while index >= 1 && key < *given_root.borrow().keys.get(index as usize - 1).unwrap() {
-                index -= 1;
-            }
-            if index >= 1 && key == *given_root.borrow().keys.get(index as usize - 1).unwrap() {
-                given_root.borrow_mut().keys.get_mut(index as usize - 1).unwrap().increase_frequency();
-                self.write(&given_root);
-            } else {
-                index += 1;
-                let mut child: Rc<RefCell<Node>> = self.read(*given_root.borrow().children_ptrs.get(index as usize - 1).unwrap());
-                if child.borrow().keys.len() == (2 * self.degree as usize) - 1 {
-                    self.btree_split_child(given_root.clone(), index as u32 - 1);
-                    if key > *given_root.borrow().keys.get(index as usize - 1).unwrap() {
-                        index += 1;
-                    }
-                    // Refresh the child node, which was changed from the split
-                    child = self.read(*given_root.borrow().children_ptrs.get(index as usize - 1).unwrap());
+            let mut child = self.read(given_root.borrow().children_ptrs[index])?;
+            if child.borrow().keys.len() as u32 == self.maximum_keys() {
+                self.btree_split_child(given_root.clone(), index as u32)?;
+                // The child's median moved up into given_root, and may be the key being inserted
+                let median = given_root.borrow().keys[index].key;
+                if key == median {
+                    return self.btree_update_at(&given_root, index, update);
+                }
+                if key > median {
+                    index += 1;
                 }
-                self.btree_insert_non_full(child, key);
+                // Refresh the child node, which was changed from the split
+                child = self.read(given_root.borrow().children_ptrs[index])?;
             }
-
+            self.btree_insert_non_full(child, key, update)
         }
     }
 
+    /// Replace the value of the key at index within given_root with update's result, and write the node back.
+    fn btree_update_at<F: FnOnce(Option<V>) -> V>(&mut self, given_root: &Rc<RefCell<Node<K, V>>>, index: usize, update: F) -> Result<()> {
+        let stored = given_root.borrow().keys[index].value;
+        given_root.borrow_mut().keys[index].value = update(Some(stored));
+        self.write(given_root)
+    }
+
     /// Get maximum allowed keys based on Btree degree
     pub fn maximum_keys(&self) -> u32 {
         2 * self.degree - 1
     }
 
     /// Returns the number of keys in the BTree.
-    pub fn get_size(&self) -> u32 {
+    pub fn get_size(&self) -> u64 {
         self.number_of_keys
     }
 
@@ -237,7 +309,7 @@ impl BTree {
     }
 
     /// Returns the number of nodes in the BTree.
-    pub fn get_number_of_nodes(&self) -> u32 {
+    pub fn get_number_of_nodes(&self) -> u64 {
         self.number_of_nodes
     }
 
@@ -246,61 +318,400 @@ impl BTree {
         self.height
     }
 
-    /// Deletes a key from the BTree. Not Implemented.
-    #[allow(unused_variables)]
-    pub fn delete(key: i32) {
-        unimplemented!();
+    /// Size in bytes of the pages nodes are aligned to.
+    pub fn get_page_size(&self) -> u32 {
+        self.pager.page_size()
     }
 
-    /// Insert a given sequence in the B-Tree. If the sequence already exists in the B-Tree,
-    /// the frequency count is incremented. Otherwise a new node is inserted
-    /// following the B-Tree insertion algorithm.
-    #[allow(unused_variables)]
-    pub fn insert<T>(&mut self, obj: T) {
-        unimplemented!();
+    /// Write every changed node in memory, including the root, and the header to file.
+    /// Until then changes to the cached nodes, root and header are only held in memory, without a cache
+    /// every other node is written once it's no longer in use. Dropping the BTree flushes it as well.
+    /// Nodes are written to the write-ahead log first, every change since the last flush is committed as one
+    /// batch when the header is written, so a crash leaves the file as it was after the last flush.
+    pub fn flush(&mut self) -> Result<()> {
+        for node in self.pool.take_dirty() {
+            self.pager.write(&node.borrow())?;
+        }
+        if self.metadata_dirty || self.pager.uncommitted() {
+            self.write_metadata()?;
+            self.metadata_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Returns ReadOnly if the BTree was opened read only.
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Number of pages freed by nodes deleted or merged away, that new nodes will reuse before the file grows.
+    pub fn free_pages(&self) -> u64 {
+        self.pager.free_pages
     }
 
-    /// Searches for a sequence in the given BTree.
-    #[allow(unused_variables)]
-    pub fn search(key: i32) -> u32 {
-        unimplemented!();
+    /// Flush the BTree and close its file, returning a summary of the BTree and how its cache was used.
+    /// Unlike dropping the BTree, errors writing it out are returned. The write-ahead log is removed and
+    /// the file unlocked either way.
+    pub fn close(mut self) -> Result<Stats> {
+        self.flush()?;
+        Ok(Stats {
+            degree: self.degree,
+            number_of_keys: self.number_of_keys,
+            number_of_nodes: self.number_of_nodes,
+            height: self.height,
+            file_size: self.pager.file_cursor,
+            free_pages: self.pager.free_pages,
+            cache_capacity: self.cache_capacity(),
+            cache: self.cache_stats(),
+        })
     }
 
-    /// Btree Decorator function, for handling writes, but either to cache or pager.
-    fn write(&mut self, node: &Rc<RefCell<Node>>){
-        if ! self.cache.is_none() {
-            self.cache.as_mut().unwrap().add_object(node.clone());
+    /// Flush the BTree and remove its write-ahead log, keeping its file locked, for a file that's about to be
+    /// replaced. The log belongs to the file's path, and would otherwise be removed from under whatever opens
+    /// the new file once the BTree is dropped.
+    pub(crate) fn close_wal(&mut self) -> Result<()> {
+        self.flush()?;
+        self.pager.close_wal();
+        Ok(())
+    }
+
+    /// Number of nodes the cache holds besides the ones in use, worked out from the memory budget when there is one.
+    /// None if the BTree isn't using a cache.
+    pub fn cache_capacity(&self) -> Option<u32> {
+        self.use_cache.then(|| self.pool.capacity())
+    }
+
+    /// Cache hits, misses, evictions and writebacks since the BTree was opened, None if it isn't using a cache.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.use_cache.then(|| self.pool.stats())
+    }
+
+    /// Length of the gene sequences stored in the BTree, 0 if it hasn't been set.
+    pub fn get_sequence_length(&self) -> u32 {
+        self.sequence_length
+    }
+
+    /// Set the length of the gene sequences stored in the BTree, kept in the file's metadata.
+    pub fn set_sequence_length(&mut self, sequence_length: u32) -> Result<()> {
+        self.check_writable()?;
+        self.sequence_length = sequence_length;
+        self.metadata_dirty = true;
+        Ok(())
+    }
+
+    /// Deletes a key from the BTree, returning the removed TreeObject if the key was found.
+    /// Follows the CLRS single pass algorithm, every child is topped up to at least degree keys
+    /// before descending into it, so a key can be removed without walking back up the tree.
+    pub fn delete(&mut self, key: K) -> Result<Option<TreeObject<K, V>>> {
+        self.btree_delete_root(key, Self::remove_key)
+    }
+
+    /// Find key in the single descent delete makes, and pass its TreeObject to update, which returns true to
+    /// remove it or false to keep it with update's changes. Children are topped up on the way down either way.
+    /// Returns the key's TreeObject as update left it, or None if the key wasn't found.
+    fn btree_delete_root<F: FnOnce(&mut TreeObject<K, V>) -> bool>(&mut self, key: K, update: F) -> Result<Option<TreeObject<K, V>>> {
+        self.check_writable()?;
+        let counts = (self.number_of_nodes, self.number_of_keys);
+        let mut removed = false;
+        let found = self.btree_delete(self.root_node.clone(), key, |obj| {
+            removed = update(obj);
+            removed
+        })?;
+        if removed {
+            // A header counting fewer keys than the tree holds is corrupt
+            self.number_of_keys = self.number_of_keys.checked_sub(1).ok_or(Error::Corrupt { offset: 0, expected: 1, found: 0 })?;
+        }
+        // Shrink the tree when a merge has emptied the root
+        let root_is_empty = self.root_node.borrow().keys.is_empty() && !self.root_node.borrow().is_leaf();
+        if root_is_empty {
+            let old_root_offset = self.root_node.borrow().offset;
+            let new_root_offset = self.root_node.borrow().children_ptrs[0];
+            self.free_node(old_root_offset)?;
+            self.root_node = self.read(new_root_offset)?;
+            // As is a header giving a height of 0 to a root with children
+            self.height = self.height.checked_sub(1).ok_or(Error::Corrupt { offset: 0, expected: 1, found: 0 })?;
         }
-        self.pager.write(&node.borrow());
+        // Merges can happen on the way down even when the key isn't found
+        if counts != (self.number_of_nodes, self.number_of_keys) {
+            self.metadata_dirty = true;
+        }
+        Ok(found)
     }
 
-    /// Btree Decorator function, for handling reads, but either to cache or pager.
-    fn read(&mut self, offset: u32) -> Rc<RefCell<Node>> {
-        if ! self.cache.is_none() {
-            match self.cache.as_mut().unwrap().get_object(offset) { 
-                Some(node) => node,
-                None => Rc::new(RefCell::new(self.pager.read(offset))),
+    /// Update for btree_delete that removes the key as it is.
+    fn remove_key(_: &mut TreeObject<K, V>) -> bool {
+        true
+    }
+
+    /// Deletes key from subtree rooted at given_root, given_root must have at least degree keys unless it's the root.
+    /// The key is only removed if update returns true.
+    fn btree_delete<F: FnOnce(&mut TreeObject<K, V>) -> bool>(&mut self, given_root: Rc<RefCell<Node<K, V>>>, key: K, update: F) -> Result<Option<TreeObject<K, V>>> {
+        let mut index = 0;
+        while index < given_root.borrow().keys.len() && key > given_root.borrow().keys[index].key {
+            index += 1;
+        }
+        let found = index < given_root.borrow().keys.len() && key == given_root.borrow().keys[index].key;
+        if given_root.borrow().is_leaf() {
+            if !found {
+                return Ok(None);
             }
+            let mut obj = given_root.borrow().keys[index];
+            if update(&mut obj) {
+                given_root.borrow_mut().keys.remove(index);
+                given_root.borrow_mut().number_of_keys -= 1;
+            } else {
+                given_root.borrow_mut().keys[index] = obj;
+            }
+            self.write(&given_root)?;
+            Ok(Some(obj))
+        } else if found {
+            self.btree_delete_internal(given_root, index, update)
         } else {
-            Rc::new(RefCell::new(self.pager.read(offset)))
+            let child = self.btree_fill_child(given_root, index)?;
+            self.btree_delete(child, key, update)
         }
     }
 
-    // TODO DO we want to return a option or result?
-    /// Read the root node from memory or disk. 
-    fn read_root(&mut self) -> Rc<RefCell<Node>> {
-        // If no offset is found, due to empty file return null
-        let offset = self.pager.get_root_offset().expect("Root Offset couldn't be found!");
-        self.read(offset)
+    /// Remove key at index from an internal node, replacing it with its predecessor or successor,
+    /// or merging both neighbouring children when neither can spare a key. The key is kept if update returns false.
+    fn btree_delete_internal<F: FnOnce(&mut TreeObject<K, V>) -> bool>(&mut self, given_root: Rc<RefCell<Node<K, V>>>, index: usize, update: F) -> Result<Option<TreeObject<K, V>>> {
+        let mut removed = given_root.borrow().keys[index];
+        if !update(&mut removed) {
+            given_root.borrow_mut().keys[index] = removed;
+            self.write(&given_root)?;
+            return Ok(Some(removed));
+        }
+        let left = self.read(given_root.borrow().children_ptrs[index])?;
+        if left.borrow().keys.len() >= self.degree as usize {
+            let predecessor = self.btree_maximum(left.clone())?;
+            given_root.borrow_mut().keys[index] = predecessor;
+            self.write(&given_root)?;
+            self.btree_delete(left, predecessor.key, Self::remove_key)?;
+            return Ok(Some(removed));
+        }
+        let right = self.read(given_root.borrow().children_ptrs[index + 1])?;
+        if right.borrow().keys.len() >= self.degree as usize {
+            let successor = self.btree_minimum(right.clone())?;
+            given_root.borrow_mut().keys[index] = successor;
+            self.write(&given_root)?;
+            self.btree_delete(right, successor.key, Self::remove_key)?;
+            return Ok(Some(removed));
+        }
+        self.btree_merge_children(&given_root, index, &left, &right)?;
+        self.btree_delete(left, removed.key, Self::remove_key)?;
+        Ok(Some(removed))
+    }
+
+    /// Make sure child at index has at least degree keys, borrowing a key through the parent from
+    /// a sibling or merging with a sibling. Returns the node the key should now be searched in.
+    fn btree_fill_child(&mut self, given_root: Rc<RefCell<Node<K, V>>>, index: usize) -> Result<Rc<RefCell<Node<K, V>>>> {
+        let child = self.read(given_root.borrow().children_ptrs[index])?;
+        if child.borrow().keys.len() >= self.degree as usize {
+            return Ok(child);
+        }
+        let left = if index > 0 {
+            Some(self.read(given_root.borrow().children_ptrs[index - 1])?)
+        } else {
+            None
+        };
+        if let Some(left) = &left {
+            if left.borrow().keys.len() >= self.degree as usize {
+                self.btree_borrow_from_left(&given_root, index, left, &child)?;
+                return Ok(child);
+            }
+        }
+        if index + 1 < given_root.borrow().children_ptrs.len() {
+            let right = self.read(given_root.borrow().children_ptrs[index + 1])?;
+            if right.borrow().keys.len() >= self.degree as usize {
+                self.btree_borrow_from_right(&given_root, index, &child, &right)?;
+            } else {
+                self.btree_merge_children(&given_root, index, &child, &right)?;
+            }
+            return Ok(child);
+        }
+        // Last child with a minimal left sibling, fold the child into its left sibling
+        let left = left.expect("Internal node has a single child");
+        self.btree_merge_children(&given_root, index - 1, &left, &child)?;
+        Ok(left)
+    }
+
+    /// Rotate the last key of the left sibling up into the parent, and the parent's separator down into child.
+    fn btree_borrow_from_left(&mut self, given_root: &Rc<RefCell<Node<K, V>>>, index: usize, left: &Rc<RefCell<Node<K, V>>>, child: &Rc<RefCell<Node<K, V>>>) -> Result<()> {
+        let borrowed = left.borrow_mut().keys.pop().unwrap();
+        let separator = std::mem::replace(&mut given_root.borrow_mut().keys[index - 1], borrowed);
+        child.borrow_mut().keys.insert(0, separator);
+        if !left.borrow().is_leaf() {
+            let child_ptr = left.borrow_mut().children_ptrs.pop().unwrap();
+            child.borrow_mut().children_ptrs.insert(0, child_ptr);
+        }
+        left.borrow_mut().number_of_keys -= 1;
+        child.borrow_mut().number_of_keys += 1;
+        self.write(left)?;
+        self.write(child)?;
+        self.write(given_root)
+    }
+
+    /// Rotate the first key of the right sibling up into the parent, and the parent's separator down into child.
+    fn btree_borrow_from_right(&mut self, given_root: &Rc<RefCell<Node<K, V>>>, index: usize, child: &Rc<RefCell<Node<K, V>>>, right: &Rc<RefCell<Node<K, V>>>) -> Result<()> {
+        let borrowed = right.borrow_mut().keys.remove(0);
+        let separator = std::mem::replace(&mut given_root.borrow_mut().keys[index], borrowed);
+        child.borrow_mut().keys.push(separator);
+        if !right.borrow().is_leaf() {
+            let child_ptr = right.borrow_mut().children_ptrs.remove(0);
+            child.borrow_mut().children_ptrs.push(child_ptr);
+        }
+        right.borrow_mut().number_of_keys -= 1;
+        child.borrow_mut().number_of_keys += 1;
+        self.write(right)?;
+        self.write(child)?;
+        self.write(given_root)
+    }
+
+    /// Merge the separator at index and the right child into the left child, freeing the right child.
+    fn btree_merge_children(&mut self, given_root: &Rc<RefCell<Node<K, V>>>, index: usize, left: &Rc<RefCell<Node<K, V>>>, right: &Rc<RefCell<Node<K, V>>>) -> Result<()> {
+        let separator = given_root.borrow_mut().keys.remove(index);
+        given_root.borrow_mut().children_ptrs.remove(index + 1);
+        given_root.borrow_mut().number_of_keys -= 1;
+        {
+            let mut left = left.borrow_mut();
+            let mut right = right.borrow_mut();
+            left.keys.push(separator);
+            left.keys.append(&mut right.keys);
+            left.children_ptrs.append(&mut right.children_ptrs);
+            left.number_of_keys = left.keys.len() as u32;
+            right.number_of_keys = 0;
+        }
+        let right_offset = right.borrow().offset;
+        self.write(left)?;
+        self.write(given_root)?;
+        self.free_node(right_offset)
+    }
+
+    /// Find the largest key in subtree, by following the rightmost children down to a leaf.
+    fn btree_maximum(&mut self, given_root: Rc<RefCell<Node<K, V>>>) -> Result<TreeObject<K, V>> {
+        let mut node = given_root;
+        while !node.borrow().is_leaf() {
+            let child_offset = *node.borrow().children_ptrs.last().unwrap();
+            node = self.read(child_offset)?;
+        }
+        let maximum = *node.borrow().keys.last().unwrap();
+        Ok(maximum)
+    }
+
+    /// Find the smallest key in subtree, by following the leftmost children down to a leaf.
+    fn btree_minimum(&mut self, given_root: Rc<RefCell<Node<K, V>>>) -> Result<TreeObject<K, V>> {
+        let mut node = given_root;
+        while !node.borrow().is_leaf() {
+            let child_offset = node.borrow().children_ptrs[0];
+            node = self.read(child_offset)?;
+        }
+        let minimum = node.borrow().keys[0];
+        Ok(minimum)
+    }
+
+    /// Release a node that is no longer part of the tree, dropping it from the buffer pool.
+    /// Its page goes on the free list, for the next new node to reuse.
+    fn free_node(&mut self, offset: u64) -> Result<()> {
+        self.pool.remove_offset(offset);
+        self.number_of_nodes = self.number_of_nodes.checked_sub(1).ok_or(Error::Corrupt { offset: 0, expected: 1, found: 0 })?;
+        self.pager.free::<K, V>(offset)
+    }
+
+    /// Write BTree's metadata to the start of file, so it can be restored when the file is opened again.
+    fn write_metadata(&mut self) -> Result<()> {
+        let metadata = Metadata {
+            page_size: self.pager.page_size(),
+            degree: self.degree,
+            sequence_length: self.sequence_length,
+            key_width: K::encoded_len() as u32,
+            value_width: V::encoded_len() as u32,
+            root_offset: self.root_node.borrow().offset,
+            number_of_nodes: self.number_of_nodes,
+            number_of_keys: self.number_of_keys,
+            height: self.height,
+            file_cursor: self.pager.file_cursor,
+            free_list: self.pager.free_list,
+            free_pages: self.pager.free_pages,
+        };
+        self.pager.write_metadata(&metadata)
+    }
+
+    /// Btree Decorator function, for handling writes. The node is marked changed in the buffer pool, and only
+    /// written to file once it's evicted or flushed.
+    fn write(&mut self, node: &Rc<RefCell<Node<K, V>>>) -> Result<()> {
+        self.add_to_pool(node.clone(), true)
+    }
+
+    /// Btree Decorator function, for handling reads. Returns a handle pinning the node in the buffer pool,
+    /// nodes not in the pool are read from the pager and added to it.
+    fn read(&mut self, offset: u64) -> Result<Rc<RefCell<Node<K, V>>>> {
+        // The root is already pinned, and isn't counted as a cache lookup
+        if offset == self.root_node.borrow().offset {
+            return Ok(self.root_node.clone());
+        }
+        if let Some(node) = self.pool.pin(offset) {
+            return Ok(node);
+        }
+        let node: Node<K, V> = self.pager.read(offset)?;
+        // Every node below the root holds at least degree - 1 keys, an empty one would be taken for a key
+        if node.keys.is_empty() {
+            return Err(Error::Corrupt { offset, expected: self.degree as u64 - 1, found: 0 });
+        }
+        let node = Rc::new(RefCell::new(node));
+        self.add_to_pool(node.clone(), false)?;
+        Ok(node)
+    }
+
+    /// Add node to the buffer pool, writing out the dirty nodes evicted to make room for it.
+    fn add_to_pool(&mut self, node: Rc<RefCell<Node<K, V>>>, dirty: bool) -> Result<()> {
+        for evicted in self.pool.insert(node, dirty) {
+            self.pager.write(&evicted.borrow())?;
+        }
+        Ok(())
     }
 
 }
 
-impl Iterator for BTree {
-    type Item = TreeObject;
-    
-    fn next(&mut self) -> Option<Self::Item> {
-        Some(TreeObject::new(1, 1))
+impl<K: Key, V: Value> Drop for BTree<K, V> {
+    /// Write out everything not yet written to file, errors can't be returned from drop so they're logged.
+    /// Call flush or close first to handle them.
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            log::error!("BTree couldn't be written to file when dropped: {err}");
+        }
+    }
+}
+
+/// K-mer counter instantiation, 2 bit encoded gene sequences keyed to their frequency.
+impl BTree {
+    /// Insert a given sequence in the B-Tree. If the sequence already exists in the B-Tree,
+    /// the frequency count is incremented. Otherwise the sequence is inserted with a frequency
+    /// of one, following the B-Tree insertion algorithm.
+    pub fn insert(&mut self, sequence: u64) -> Result<()> {
+        self.btree_insert(sequence, |frequency| frequency.map_or(1, |frequency| frequency + 1))
+    }
+
+    /// Decrease the frequency of a sequence by count, deleting the sequence once its frequency reaches zero.
+    /// Returns the remaining frequency, or None if the sequence isn't in the BTree.
+    /// Takes a single descent from the root, the same one delete makes.
+    pub fn decrease_frequency(&mut self, sequence: u64, count: u64) -> Result<Option<u64>> {
+        let obj = self.btree_delete_root(sequence, |obj| {
+            obj.decrease_frequency(count);
+            obj.value == 0
+        })?;
+        Ok(obj.map(|obj| obj.value))
+    }
+}
+
+impl<'a, K: Key, V: Value> IntoIterator for &'a mut BTree<K, V> {
+    type Item = Result<TreeObject<K, V>>;
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
     }
 }
 
@@ -317,12 +728,12 @@ mod tests {
     fn btree(degree: u32, file_name: &str) -> BTree {
         let use_cache = true;
         let cache_size = 100;
-        BTree::new(degree, file_name, use_cache, cache_size, true)
+        BTree::new(degree, file_name, use_cache, cache_size, true).unwrap()
     }
 
     /// Verify btree elements are in same order as actual elements passed in.
     fn validate_btree_inserts(mut b: BTree, input_keys: Vec<u64>) -> bool {
-        let mut btree_keys = b.get_sorted_key_array();
+        let mut btree_keys = b.get_sorted_key_array().unwrap();
         // input may be unsorted
         btree_keys.sort();
         // track input as a dynamic set to easily remove duplicates
@@ -345,16 +756,60 @@ mod tests {
 
         let prev: u64 = btree_keys[0];
 
-        for i in 0..btree_keys.len() {
-            if btree_keys[i] != input_no_duplicates.get(i).unwrap().clone() {
+        for (i, key) in btree_keys.iter().enumerate() {
+            if *key != input_no_duplicates[i] {
                 return false;
             }
 
-            if i > 0 && prev > btree_keys[i] {
+            if i > 0 && prev > *key {
                 return false;
             }
         }
-        return true;
+        true
+    }
+
+    /// Walk every node checking B-Tree invariants: sorted keys, key count bounds, equal leaf depth,
+    /// and that the copy of each node handed out by the BTree matches what is on disk.
+    /// Returns the number of nodes visited.
+    fn validate_btree_structure(b: &mut BTree) -> u64 {
+        // Nodes are compared against their copy on disk
+        b.flush().unwrap();
+        let root_offset = b.root_node.borrow().offset;
+        let mut leaf_depth = None;
+        validate_node(b, root_offset, 0, &mut leaf_depth, None, None)
+    }
+
+    fn validate_node(b: &mut BTree, offset: u64, depth: u32, leaf_depth: &mut Option<u32>, lower: Option<u64>, upper: Option<u64>) -> u64 {
+        let node = b.read(offset).unwrap();
+        let node = node.borrow();
+        assert_eq!(*node, b.pager.read(offset).unwrap(), "Node at {offset} differs from disk");
+        assert_eq!(0, offset % b.get_page_size() as u64, "Node at {offset} isn't page aligned");
+        assert_eq!(node.number_of_keys as usize, node.keys.len());
+        assert!(node.keys.len() as u32 <= b.maximum_keys());
+        if depth > 0 {
+            assert!(node.keys.len() as u32 >= b.degree - 1, "Node at {offset} is under full");
+        }
+        for pair in node.keys.windows(2) {
+            assert!(pair[0] < pair[1]);
+        }
+        if let (Some(lower), Some(first)) = (lower, node.keys.first()) {
+            assert!(first.key > lower);
+        }
+        if let (Some(upper), Some(last)) = (upper, node.keys.last()) {
+            assert!(last.key < upper);
+        }
+        if node.is_leaf() {
+            assert_eq!(*leaf_depth.get_or_insert(depth), depth, "Leaves at different depths");
+            return 1;
+        }
+        assert_eq!(node.children_ptrs.len(), node.keys.len() + 1);
+        let mut visited = 1;
+        for (i, child) in node.children_ptrs.iter().enumerate() {
+            let child_lower = if i == 0 { lower } else { Some(node.keys[i - 1].key) };
+            let child_upper = node.keys.get(i).map(|x| x.key).or(upper);
+            visited += validate_node(b, *child, depth + 1, leaf_depth, child_lower, child_upper);
+        }
+        visited
     }
 
     /// Test simple creation of an empty BTree.
@@ -363,13 +818,67 @@ mod tests {
     fn test_btree_create() {
         let file_name = "test_btree_create.tmp";
         delete_file(file_name);
-        let b: BTree = btree(1, file_name);
+        let b: BTree = btree(2, file_name);
         assert_eq!(0, b.height);
         assert_eq!(0, b.get_size());    
         assert_eq!(1, b.number_of_nodes);
         delete_file(file_name);
     }
 
+    /// A node below the root with no keys, or a header counting fewer keys or levels than the tree holds,
+    /// is reported as Corrupt instead of panicking.
+    #[test]
+    fn test_btree_corrupt_counts() {
+        let file_name = "test_btree_corrupt_counts.tmp";
+        delete_file(file_name);
+        let mut b: BTree = BTree::new(2, file_name, false, 0, true).unwrap();
+        for i in 0..5 {
+            b.insert(i).unwrap();
+        }
+        let leaf_offset = b.root_node.borrow().children_ptrs[0];
+        drop(b);
+        let mut pager = Pager::new(file_name, 2, pager::DEFAULT_PAGE_SIZE).unwrap();
+        let mut leaf: Node = pager.read(leaf_offset).unwrap();
+        leaf.keys.clear();
+        leaf.number_of_keys = 0;
+        pager.write(&leaf).unwrap();
+        drop(pager);
+        let mut b: BTree = BTree::new(2, file_name, false, 0, false).unwrap();
+        assert!(matches!(b.get(0), Err(Error::Corrupt { offset, expected: 1, found: 0 }) if offset == leaf_offset));
+        assert!(matches!(b.delete(0), Err(Error::Corrupt { offset, expected: 1, found: 0 }) if offset == leaf_offset));
+        drop(b);
+
+        delete_file(file_name);
+        let mut b: BTree = BTree::new(2, file_name, false, 0, true).unwrap();
+        b.insert(1).unwrap();
+        b.number_of_keys = 0;
+        assert!(matches!(b.delete(1), Err(Error::Corrupt { offset: 0, expected: 1, found: 0 })));
+        drop(b);
+
+        // A header giving a height of 0 to a root with children
+        delete_file(file_name);
+        let mut b: BTree = BTree::new(2, file_name, false, 0, true).unwrap();
+        for i in 0..4 {
+            b.insert(i).unwrap();
+        }
+        b.height = 0;
+        b.delete(3).unwrap();
+        // Merging the last two leaves empties the root
+        assert!(matches!(b.delete(2), Err(Error::Corrupt { offset: 0, expected: 1, found: 0 })));
+        drop(b);
+        delete_file(file_name);
+    }
+
+    /// A BTree needs a degree of at least 2, the file isn't created with a smaller one.
+    #[test]
+    fn test_btree_create_invalid_degree() {
+        let file_name = "test_btree_create_invalid_degree.tmp";
+        delete_file(file_name);
+        let result: Result<BTree> = BTree::new(1, file_name, true, 100, true);
+        assert!(matches!(result, Err(Error::InvalidDegree(1))));
+        assert!(!std::path::Path::new(file_name).exists());
+    }
+
     /// Test constructing a BTree with custom degree.
     #[test]
     fn test_btree_create_degree() {
@@ -389,7 +898,7 @@ mod tests {
         let file_name = "test_insert_one_key.tmp";
         delete_file(file_name);
         let mut b: BTree = btree(2, file_name);
-        b.btree_insert(TreeObject { sequence: 1, frequency: 1 });
+        b.insert(1).unwrap();
         assert_eq!(1, b.get_size());
         assert_eq!(0, b.get_height());
         delete_file(file_name);
@@ -402,13 +911,13 @@ mod tests {
         delete_file(file_name);
         let mut b: BTree = btree(3, file_name); // Chould split at 5 keys
         let input = vec![7, 23, 59, 67, 73, 97];
-        b.btree_insert(TreeObject {sequence: 59 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 23 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 7 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 97 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 73 as u64, frequency: 1 });
+        b.insert(59).unwrap();
+        b.insert(23).unwrap();
+        b.insert(7).unwrap();
+        b.insert(97).unwrap();
+        b.insert(73).unwrap();
         // split
-        b.btree_insert(TreeObject {sequence: 67 as u64, frequency: 1 });
+        b.insert(67).unwrap();
         assert_eq!(6, b.get_size());
         assert_eq!(1, b.get_height());
         assert!(validate_btree_inserts(b, input));
@@ -423,17 +932,17 @@ mod tests {
         delete_file(file_name);
         let mut b: BTree = btree(3, file_name); // Chould split at 5 keys
         let input = vec![7, 19, 23, 41, 59, 61, 67, 73, 79, 97];
-        b.btree_insert(TreeObject {sequence: 59 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 23 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 7 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 97 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 73 as u64, frequency: 1 });
+        b.insert(59).unwrap();
+        b.insert(23).unwrap();
+        b.insert(7).unwrap();
+        b.insert(97).unwrap();
+        b.insert(73).unwrap();
         // split
-        b.btree_insert(TreeObject {sequence: 67 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 19 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 79 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 61 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 41 as u64, frequency: 1 });
+        b.insert(67).unwrap();
+        b.insert(19).unwrap();
+        b.insert(79).unwrap();
+        b.insert(61).unwrap();
+        b.insert(41).unwrap();
         assert_eq!(10, b.get_size());
         assert_eq!(1, b.get_height());
         assert!(validate_btree_inserts(b, input));
@@ -448,19 +957,19 @@ mod tests {
         delete_file(file_name);
         let mut b: BTree = btree(3, file_name); // Chould split at 5 keys
         let input = vec![7, 19, 23, 41, 59, 61, 67, 73, 74, 79, 97];
-        b.btree_insert(TreeObject {sequence: 59 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 23 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 7 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 97 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 73 as u64, frequency: 1 });
+        b.insert(59).unwrap();
+        b.insert(23).unwrap();
+        b.insert(7).unwrap();
+        b.insert(97).unwrap();
+        b.insert(73).unwrap();
         // split
-        b.btree_insert(TreeObject {sequence: 67 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 19 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 79 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 61 as u64, frequency: 1 });
-        b.btree_insert(TreeObject {sequence: 41 as u64, frequency: 1 });
+        b.insert(67).unwrap();
+        b.insert(19).unwrap();
+        b.insert(79).unwrap();
+        b.insert(61).unwrap();
+        b.insert(41).unwrap();
         // split
-        b.btree_insert(TreeObject {sequence: 74 as u64, frequency: 1 });
+        b.insert(74).unwrap();
 
         assert_eq!(11, b.get_size());
         assert_eq!(1, b.get_height());
@@ -477,7 +986,7 @@ mod tests {
         let mut input = Vec::new();
         for i in 0..10 {
             input.push(i);
-            b.btree_insert(TreeObject {sequence: i as u64, frequency: 1 })
+            b.insert(i).unwrap()
         }
         assert_eq!(10, b.get_size());
         assert_eq!(2, b.get_height());
@@ -494,7 +1003,7 @@ mod tests {
         let mut input = Vec::new();
         for i in (0..10).rev() {
             input.push(i);
-            b.btree_insert(TreeObject {sequence: i as u64, frequency: 1 })
+            b.insert(i).unwrap()
         }
         input.reverse();
         assert_eq!(10, b.get_size());
@@ -512,7 +1021,7 @@ mod tests {
         let mut b: BTree = btree(2, file_name);
         let input = vec![1,1,1,1,1,1,1,1,1,1];
         for _ in 0..10 {
-            b.btree_insert(TreeObject {sequence: 1, frequency: 1 })
+            b.insert(1).unwrap()
         }
 
         assert_eq!(1, b.get_size());
@@ -521,4 +1030,593 @@ mod tests {
         delete_file(file_name);
     }
 
+    /// Deleting a key from a single leaf root removes only that key.
+    #[test]
+    fn test_delete_from_leaf() {
+        let file_name = "test_delete_from_leaf.tmp";
+        delete_file(file_name);
+        let mut b: BTree = btree(3, file_name);
+        for i in [59, 23, 7, 97] {
+            b.insert(i).unwrap();
+        }
+        let removed = b.delete(23).unwrap().unwrap();
+        assert_eq!(23, removed.key);
+        assert_eq!(3, b.get_size());
+        assert!(!b.contains(23).unwrap());
+        assert!(validate_btree_inserts(b, vec![7, 59, 97]));
+        delete_file(file_name);
+    }
+
+    /// Deleting a sequence that was never inserted leaves the tree untouched.
+    #[test]
+    fn test_delete_missing_key() {
+        let file_name = "test_delete_missing_key.tmp";
+        delete_file(file_name);
+        let mut b: BTree = btree(2, file_name);
+        for i in 0..10 {
+            b.insert(i * 2).unwrap();
+        }
+        assert!(b.delete(7).unwrap().is_none());
+        assert_eq!(10, b.get_size());
+        assert_eq!(2, b.get_height());
+        delete_file(file_name);
+    }
+
+    /// Removing keys from a tree of degree 2 forces borrows and merges, until the root collapses.
+    #[test]
+    fn test_delete_shrinks_root() {
+        let file_name = "test_delete_shrinks_root.tmp";
+        delete_file(file_name);
+        let mut b: BTree = btree(2, file_name);
+        for i in 0..10 {
+            b.insert(i).unwrap();
+        }
+        assert_eq!(2, b.get_height());
+        for i in 0..9 {
+            assert_eq!(i, b.delete(i).unwrap().unwrap().key);
+            assert_eq!(b.get_number_of_nodes(), validate_btree_structure(&mut b));
+        }
+        assert_eq!(1, b.get_size());
+        assert_eq!(0, b.get_height());
+        assert_eq!(1, b.get_number_of_nodes());
+        assert!(validate_btree_inserts(b, vec![9]));
+        delete_file(file_name);
+    }
+
+    /// Internal keys are replaced by predecessor or successor, keeping their frequency.
+    #[test]
+    fn test_delete_internal_key_keeps_frequencies() {
+        let file_name = "test_delete_internal_key_keeps_frequencies.tmp";
+        delete_file(file_name);
+        let mut b: BTree = btree(2, file_name);
+        for i in 0..10 {
+            for _ in 0..=i {
+                b.insert(i).unwrap();
+            }
+        }
+        let root_key = b.root_node.borrow().keys[0];
+        assert_eq!(root_key.value, b.delete(root_key.key).unwrap().unwrap().value);
+        assert_eq!(b.get_number_of_nodes(), validate_btree_structure(&mut b));
+        for key in b.get_sorted_array().unwrap() {
+            assert_eq!(key.key + 1, key.value);
+        }
+        delete_file(file_name);
+    }
+
+    /// Insert and then delete shuffled keys, checking the tree after every delete, with and without a cache.
+    #[test]
+    fn test_delete_all_keys_random_order() {
+        use rand::seq::SliceRandom;
+        for use_cache in [true, false] {
+            let file_name = "test_delete_all_keys_random_order.tmp";
+            delete_file(file_name);
+            let mut b: BTree = BTree::new(2, file_name, use_cache, 5, true).unwrap();
+            let mut rng = rand::thread_rng();
+            let mut input: Vec<u64> = (0..200).collect();
+            input.shuffle(&mut rng);
+            for i in input.iter() {
+                b.insert(*i).unwrap();
+            }
+            input.shuffle(&mut rng);
+            for (deleted, i) in input.iter().enumerate() {
+                assert_eq!(*i, b.delete(*i).unwrap().unwrap().key);
+                assert!(!b.contains(*i).unwrap());
+                assert_eq!(input.len() - deleted - 1, b.get_size() as usize);
+                assert_eq!(b.get_number_of_nodes(), validate_btree_structure(&mut b));
+            }
+            assert_eq!(0, b.get_height());
+            assert!(b.get_sorted_key_array().unwrap().is_empty());
+            delete_file(file_name);
+        }
+    }
+
+    /// Decreasing a frequency leaves the sequence in place until it runs out, then deletes it.
+    #[test]
+    fn test_decrease_frequency() {
+        let file_name = "test_decrease_frequency.tmp";
+        delete_file(file_name);
+        let mut b: BTree = btree(2, file_name);
+        for i in 0..10 {
+            for _ in 0..5 {
+                b.insert(i).unwrap();
+            }
+        }
+        assert_eq!(Some(3), b.decrease_frequency(4, 2).unwrap());
+        assert_eq!(Some(3), b.get(4).unwrap());
+        assert_eq!(10, b.get_size());
+        assert_eq!(Some(0), b.decrease_frequency(4, 3).unwrap());
+        assert!(!b.contains(4).unwrap());
+        assert_eq!(Some(0), b.decrease_frequency(5, 100).unwrap());
+        assert_eq!(None, b.decrease_frequency(5, 1).unwrap());
+        assert_eq!(8, b.get_size());
+        assert_eq!(b.get_number_of_nodes(), validate_btree_structure(&mut b));
+        delete_file(file_name);
+    }
+
+    /// A BTree over wider keys and array values, like long k-mers with per sample counts.
+    #[test]
+    fn test_generic_key_value() {
+        let file_name = "test_generic_key_value.tmp";
+        delete_file(file_name);
+        let mut b: BTree<u128, [u32; 4]> = BTree::new(2, file_name, true, 10, true).unwrap();
+        for i in 0..50u128 {
+            let key = (i << 64) | i;
+            b.upsert_with(key, |counts| counts.unwrap_or([i as u32, 0, 0, 0])).unwrap();
+        }
+        b.upsert_with((7 << 64) | 7, |counts| {
+            let mut counts = counts.unwrap();
+            counts[3] += 1;
+            counts
+        }).unwrap();
+        assert_eq!(50, b.get_size());
+        assert_eq!(Some([7, 0, 0, 1]), b.get((7 << 64) | 7).unwrap());
+        assert_eq!([9, 0, 0, 0], b.delete((9 << 64) | 9).unwrap().unwrap().value);
+        assert!(!b.contains((9 << 64) | 9).unwrap());
+        let keys = b.get_sorted_key_array().unwrap();
+        assert_eq!(49, keys.len());
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        delete_file(file_name);
+    }
+
+    /// Inserting a sequence that equals the median of a child being split must count it, not store it twice.
+    #[test]
+    fn test_insert_duplicates_across_splits() {
+        use rand::seq::SliceRandom;
+        let file_name = "test_insert_duplicates_across_splits.tmp";
+        delete_file(file_name);
+        let mut b: BTree = btree(2, file_name);
+        let mut input: Vec<u64> = (0..100).chain(0..100).chain(0..100).collect();
+        input.shuffle(&mut rand::thread_rng());
+        for i in input {
+            b.insert(i).unwrap();
+        }
+        assert_eq!(100, b.get_size());
+        assert_eq!(b.get_number_of_nodes(), validate_btree_structure(&mut b));
+        for i in 0..100 {
+            assert_eq!(Some(3), b.get(i).unwrap());
+        }
+        assert_eq!(None, b.get(100).unwrap());
+        delete_file(file_name);
+    }
+
+    /// Changes stay in memory until flushed, with or without a cache, and flushing writes everything needed to reopen the BTree.
+    #[test]
+    fn test_flush_writes_back() {
+        let file_name = "test_flush_writes_back.tmp";
+        for use_cache in [true, false] {
+            delete_file(file_name);
+            let mut b: BTree = BTree::new(2, file_name, use_cache, 1000, true).unwrap();
+            for i in 0..300 {
+                b.insert(i).unwrap();
+            }
+            // The header on disk is still the one written when the file was created, read past the open BTree
+            let header = || Pager::new(file_name, 2, pager::DEFAULT_PAGE_SIZE).unwrap().read_metadata().unwrap();
+            assert_eq!(0, header().number_of_keys);
+            b.flush().unwrap();
+            assert_eq!(300, header().number_of_keys);
+            // Dropping flushes as well
+            b.delete(42).unwrap();
+            drop(b);
+            let mut b: BTree = BTree::new(2, file_name, true, 1000, false).unwrap();
+            assert_eq!(299, b.get_size());
+            assert_eq!((0..300).filter(|&i| i != 42).collect::<Vec<u64>>(), b.get_sorted_key_array().unwrap());
+            assert_eq!(b.get_number_of_nodes(), validate_btree_structure(&mut b));
+        }
+        delete_file(file_name);
+    }
+
+    /// Pages of nodes merged away are kept on the free list across reopening, and reused before the file grows.
+    #[test]
+    fn test_free_pages_reused() {
+        let file_name = "test_free_pages_reused.tmp";
+        for use_cache in [true, false] {
+            delete_file(file_name);
+            let mut b: BTree = BTree::new(2, file_name, use_cache, 10, true).unwrap();
+            for i in 0..500 {
+                b.insert(i).unwrap();
+            }
+            let nodes = b.get_number_of_nodes();
+            assert_eq!(0, b.free_pages());
+            for i in 0..400 {
+                b.delete(i).unwrap();
+            }
+            assert_eq!(nodes - b.get_number_of_nodes(), b.free_pages());
+            let file_size = b.close().unwrap().file_size;
+            let mut b: BTree = BTree::new(2, file_name, use_cache, 10, false).unwrap();
+            assert_eq!(nodes - b.get_number_of_nodes(), b.free_pages());
+            // Every new node takes a free page until there are none left
+            let mut i = 0;
+            while b.free_pages() > 0 {
+                b.insert(1000 + i).unwrap();
+                assert_eq!(file_size, b.pager.file_cursor);
+                i += 1;
+            }
+            assert_eq!(b.get_number_of_nodes(), validate_btree_structure(&mut b));
+            assert_eq!((400..500).chain(1000..1000 + i).collect::<Vec<u64>>(), b.get_sorted_key_array().unwrap());
+            drop(b);
+            assert_eq!(file_size, std::fs::metadata(file_name).unwrap().len());
+        }
+        delete_file(file_name);
+    }
+
+    /// Closing writes everything out like dropping does, and sums up the BTree as it was written.
+    #[test]
+    fn test_close() {
+        let file_name = "test_close.tmp";
+        for use_cache in [true, false] {
+            delete_file(file_name);
+            let mut b: BTree = BTree::new(2, file_name, use_cache, 50, true).unwrap();
+            for i in 0..300 {
+                b.insert(i % 200).unwrap();
+            }
+            let stats = b.close().unwrap();
+            assert_eq!((2, 200), (stats.degree, stats.number_of_keys));
+            assert_eq!(std::fs::metadata(file_name).unwrap().len(), stats.file_size);
+            assert_eq!(use_cache, stats.cache.is_some_and(|cache| cache.hits > 0));
+            assert_eq!(use_cache.then_some(50), stats.cache_capacity);
+            assert!(!std::path::Path::new(&wal::wal_path(file_name)).exists());
+            let mut b: BTree = BTree::new(2, file_name, true, 50, false).unwrap();
+            assert_eq!((stats.number_of_nodes, stats.height), (b.get_number_of_nodes(), b.get_height()));
+            assert_eq!(stats.number_of_nodes, validate_btree_structure(&mut b));
+            assert_eq!(Some(2), b.get(50).unwrap());
+            assert_eq!(Some(1), b.get(150).unwrap());
+        }
+        delete_file(file_name);
+    }
+
+    /// A crash between flushes leaves the file as it was after the last flush, however many nodes were written since.
+    #[test]
+    fn test_crash_keeps_last_flush() {
+        let file_name = "test_crash_keeps_last_flush.tmp";
+        let crashed_file = "test_crash_keeps_last_flush.crashed.tmp";
+        for use_cache in [true, false] {
+            delete_file(file_name);
+            let mut b: BTree = BTree::new(2, file_name, use_cache, 5, true).unwrap();
+            for i in 0..200 {
+                b.insert(i).unwrap();
+            }
+            b.flush().unwrap();
+            for i in 100..400 {
+                b.insert(i).unwrap();
+            }
+            // Copy the file and its log as they are on disk, as a crash would leave them
+            assert!(b.pager.uncommitted());
+            std::fs::copy(file_name, crashed_file).unwrap();
+            std::fs::copy(wal::wal_path(file_name), wal::wal_path(crashed_file)).unwrap();
+            let mut crashed: BTree = BTree::new(2, crashed_file, true, 100, false).unwrap();
+            assert_eq!(200, crashed.get_size());
+            assert_eq!(vec![1; 200], crashed.iter().map(|obj| obj.unwrap().value).collect::<Vec<u64>>());
+            assert_eq!(crashed.get_number_of_nodes(), validate_btree_structure(&mut crashed));
+            drop(crashed);
+            assert!(!std::path::Path::new(&wal::wal_path(crashed_file)).exists());
+            drop(b);
+            let mut b: BTree = BTree::new(2, file_name, true, 100, false).unwrap();
+            assert_eq!(400, b.get_size());
+            assert_eq!(b.get_number_of_nodes(), validate_btree_structure(&mut b));
+        }
+        delete_file(file_name);
+        delete_file(crashed_file);
+    }
+
+    /// A file can only be open as one BTree at a time.
+    #[test]
+    fn test_file_in_use() {
+        let file_name = "test_file_in_use.tmp";
+        delete_file(file_name);
+        let b: BTree = BTree::new(2, file_name, true, 100, true).unwrap();
+        let result: Result<BTree> = BTree::new(2, file_name, true, 100, false);
+        assert!(matches!(result, Err(Error::FileInUse)));
+        drop(b);
+        assert!(BTree::<u64, u64>::new(2, file_name, true, 100, false).is_ok());
+        delete_file(file_name);
+    }
+
+    /// Opening a file in use to truncate it fails before the file, or the batch in its log, is thrown away.
+    #[test]
+    fn test_file_in_use_truncate() {
+        let file_name = "test_file_in_use_truncate.tmp";
+        delete_file(file_name);
+        let mut b: BTree = BTree::new(2, file_name, true, 100, true).unwrap();
+        for i in 0..100 {
+            b.insert(i).unwrap();
+        }
+        b.flush().unwrap();
+        // Logged but not yet committed
+        b.insert(100).unwrap();
+        b.pool.take_dirty().iter().for_each(|node| b.pager.write(&node.borrow()).unwrap());
+        assert!(b.pager.uncommitted());
+        let result: Result<BTree> = BTree::new(2, file_name, true, 100, true);
+        assert!(matches!(result, Err(Error::FileInUse)));
+        b.close().unwrap();
+        let mut b: BTree = BTree::new(2, file_name, true, 100, false).unwrap();
+        assert_eq!((0..=100).collect::<Vec<u64>>(), b.get_sorted_key_array().unwrap());
+        delete_file(file_name);
+    }
+
+    /// Read only BTrees can share a file with each other but not with a BTree that writes to it,
+    /// and don't leave a write-ahead log behind.
+    #[test]
+    fn test_read_only() {
+        let file_name = "test_read_only.tmp";
+        delete_file(file_name);
+        let read_only = BTreeOptions { read_only: true, ..Default::default() };
+        assert!(matches!(BTree::<u64, u64>::open(file_name, read_only), Err(Error::Io(_))));
+        assert!(!std::path::Path::new(file_name).exists());
+        let mut b: BTree = BTree::new(2, file_name, true, 100, true).unwrap();
+        for i in 0..100 {
+            b.insert(i).unwrap();
+        }
+        assert!(matches!(BTree::<u64, u64>::open(file_name, read_only), Err(Error::FileInUse)));
+        b.close().unwrap();
+
+        // Without asking for write access to the file
+        let writable = std::fs::metadata(file_name).unwrap().permissions();
+        let mut permissions = writable.clone();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(file_name, permissions).unwrap();
+        let mut first: BTree = BTree::open(file_name, read_only).unwrap();
+        let mut second: BTree = BTree::open(file_name, read_only).unwrap();
+        assert!(!std::path::Path::new(&wal::wal_path(file_name)).exists());
+        assert_eq!((0..100).collect::<Vec<u64>>(), first.get_sorted_key_array().unwrap());
+        assert_eq!(Some(1), second.get(42).unwrap());
+        assert!(matches!(BTree::<u64, u64>::new(2, file_name, true, 100, false), Err(Error::FileInUse)));
+        assert!(matches!(BTree::<u64, u64>::new(2, file_name, true, 100, true), Err(Error::FileInUse)));
+        assert!(matches!(first.insert(100), Err(Error::ReadOnly)));
+        assert!(matches!(first.delete(0), Err(Error::ReadOnly)));
+        assert!(matches!(first.decrease_frequency(0, 1), Err(Error::ReadOnly)));
+        assert!(matches!(first.set_sequence_length(5), Err(Error::ReadOnly)));
+        assert!(matches!(BTree::<u64, u64>::open(file_name, BTreeOptions { truncate_file: true, ..read_only }), Err(Error::ReadOnly)));
+        drop(first);
+        drop(second);
+        std::fs::set_permissions(file_name, writable).unwrap();
+        let mut b: BTree = BTree::new(2, file_name, true, 100, false).unwrap();
+        assert_eq!((0..100).collect::<Vec<u64>>(), b.get_sorted_key_array().unwrap());
+        delete_file(file_name);
+    }
+
+    /// A read only open still clears up the log left by a crash, before sharing the file.
+    #[test]
+    fn test_read_only_after_crash() {
+        let file_name = "test_read_only_after_crash.tmp";
+        let crashed_file = "test_read_only_after_crash.crashed.tmp";
+        delete_file(file_name);
+        let mut b: BTree = BTree::new(2, file_name, true, 5, true).unwrap();
+        for i in 0..200 {
+            b.insert(i).unwrap();
+        }
+        b.flush().unwrap();
+        for i in 100..400 {
+            b.insert(i).unwrap();
+        }
+        assert!(b.pager.uncommitted());
+        std::fs::copy(file_name, crashed_file).unwrap();
+        std::fs::copy(wal::wal_path(file_name), wal::wal_path(crashed_file)).unwrap();
+        let read_only = BTreeOptions { read_only: true, ..Default::default() };
+        let mut crashed: BTree = BTree::open(crashed_file, read_only).unwrap();
+        assert!(!std::path::Path::new(&wal::wal_path(crashed_file)).exists());
+        let second: BTree = BTree::open(crashed_file, read_only).unwrap();
+        assert_eq!(vec![1; 200], crashed.iter().map(|obj| obj.unwrap().value).collect::<Vec<u64>>());
+        assert_eq!(200, second.get_size());
+        drop(b);
+        delete_file(file_name);
+        delete_file(crashed_file);
+    }
+
+    /// Cache counters add up as nodes are read, evicted and written back, and are only kept when there is a cache.
+    #[test]
+    fn test_cache_stats() {
+        let file_name = "test_cache_stats.tmp";
+        delete_file(file_name);
+        let mut b: BTree = BTree::new(2, file_name, true, 5, true).unwrap();
+        assert_eq!(Some(CacheStats::default()), b.cache_stats());
+        for i in 0..200 {
+            b.insert(i).unwrap();
+        }
+        let stats = b.cache_stats().unwrap();
+        assert!(stats.hits > 0 && stats.misses > 0 && stats.evictions > 0 && stats.writebacks > 0);
+        b.flush().unwrap();
+        let flushed = b.cache_stats().unwrap();
+        assert!(flushed.writebacks > stats.writebacks);
+        let stats = flushed;
+        b.get(0).unwrap();
+        let after = b.cache_stats().unwrap();
+        // One lookup for every node below the root, down to the leaf holding 0
+        assert_eq!(b.get_height() as u64, (after.hits + after.misses) - (stats.hits + stats.misses));
+        drop(b);
+        let b: BTree = BTree::new(2, file_name, false, 0, false).unwrap();
+        assert_eq!(None, b.cache_stats());
+        delete_file(file_name);
+    }
+
+    /// A memory budget holds as many nodes as fit in it at the file's degree, whatever degree is asked for,
+    /// counting what the pool keeps for each node in memory as well as the node.
+    #[test]
+    fn test_cache_memory() {
+        let file_name = "test_cache_memory.tmp";
+        delete_file(file_name);
+        let budget = 1 << 20;
+        let memory = |degree, policy| buffer_pool::node_memory::<u64, u64>(degree, policy);
+        // Small nodes take up more than twice their 101 bytes on disk
+        assert!(memory(2, CachePolicy::Lru) > 2 * pager::node_size(2, 16) as u64);
+        assert!(memory(2, CachePolicy::Arc) > memory(2, CachePolicy::Lru));
+        let options = BTreeOptions { degree: 2, cache_memory: Some(budget), truncate_file: true, ..Default::default() };
+        let b: BTree = BTree::open(file_name, options).unwrap();
+        assert_eq!(Some((budget / memory(2, CachePolicy::Lru)) as u32), b.cache_capacity());
+        drop(b);
+        let options = BTreeOptions { cache_memory: Some(budget), cache_policy: CachePolicy::Arc, ..Default::default() };
+        let b: BTree = BTree::open(file_name, options).unwrap();
+        assert_eq!(Some((budget / memory(2, CachePolicy::Arc)) as u32), b.cache_capacity());
+        drop(b);
+        let options = BTreeOptions { cache_memory: Some(budget), truncate_file: true, ..Default::default() };
+        let b: BTree = BTree::open(file_name, options).unwrap();
+        assert_eq!(Some((budget / memory(85, CachePolicy::Lru)) as u32), b.cache_capacity());
+        drop(b);
+        let options = BTreeOptions { use_cache: false, cache_memory: Some(budget), ..Default::default() };
+        let b: BTree = BTree::open(file_name, options).unwrap();
+        assert_eq!(None, b.cache_capacity());
+        drop(b);
+        // A budget far larger than the tree doesn't allocate anything up front
+        let options = BTreeOptions { cache_memory: Some(u64::MAX), ..Default::default() };
+        let b: BTree = BTree::open(file_name, options).unwrap();
+        assert_eq!(Some(u32::MAX), b.cache_capacity());
+        drop(b);
+        delete_file(file_name);
+    }
+
+    /// A node read while a handle to it is held is the same instance, even after enough other reads to evict
+    /// it from a cache, and changes made through the handle are what gets written.
+    #[test]
+    fn test_read_pins_node() {
+        let file_name = "test_read_pins_node.tmp";
+        for (use_cache, cache_size) in [(true, 1), (false, 0)] {
+            delete_file(file_name);
+            let mut b: BTree = BTree::new(2, file_name, use_cache, cache_size, true).unwrap();
+            for i in 0..100 {
+                b.insert(i * 2).unwrap();
+            }
+            let leaf_offset = b.root_node.borrow().children_ptrs[0];
+            let leaf = b.read(leaf_offset).unwrap();
+            for i in 0..50 {
+                b.insert(i * 2 + 101).unwrap();
+            }
+            let again = b.read(leaf_offset).unwrap();
+            assert!(Rc::ptr_eq(&leaf, &again));
+            again.borrow_mut().keys[0].value = 42;
+            b.write(&again).unwrap();
+            drop((leaf, again));
+            b.flush().unwrap();
+            let node: Node = b.pager.read(leaf_offset).unwrap();
+            assert_eq!(42, node.keys[0].value);
+            drop(b);
+        }
+        delete_file(file_name);
+    }
+
+    /// Reopening a BTree file restores its metadata, and inserts carry on after the existing nodes.
+    #[test]
+    fn test_reopen_restores_metadata() {
+        let file_name = "test_reopen_restores_metadata.tmp";
+        delete_file(file_name);
+        let mut b: BTree = btree(2, file_name);
+        b.set_sequence_length(5).unwrap();
+        for i in 0..100 {
+            b.insert(i).unwrap();
+        }
+        let (nodes, height) = (b.get_number_of_nodes(), b.get_height());
+        drop(b);
+        // Degree comes from the file, not the constructor
+        let mut b: BTree = BTree::new(0, file_name, true, 100, false).unwrap();
+        assert_eq!(2, b.get_degree());
+        assert_eq!(5, b.get_sequence_length());
+        assert_eq!(100, b.get_size());
+        assert_eq!(nodes, b.get_number_of_nodes());
+        assert_eq!(height, b.get_height());
+        for i in 50..150 {
+            b.insert(i).unwrap();
+        }
+        assert_eq!(b.get_number_of_nodes(), validate_btree_structure(&mut b));
+        drop(b);
+        let mut b: BTree = BTree::new(2, file_name, false, 0, false).unwrap();
+        assert_eq!(150, b.get_size());
+        assert_eq!(b.get_number_of_nodes(), validate_btree_structure(&mut b));
+        for i in 0..150 {
+            assert_eq!(Some(if (50..100).contains(&i) { 2 } else { 1 }), b.get(i).unwrap());
+        }
+        delete_file(file_name);
+    }
+
+    /// Deletes are kept in the metadata too, including the root shrinking.
+    #[test]
+    fn test_reopen_after_delete() {
+        let file_name = "test_reopen_after_delete.tmp";
+        delete_file(file_name);
+        let mut b: BTree = btree(2, file_name);
+        for i in 0..10 {
+            b.insert(i).unwrap();
+        }
+        for i in 0..9 {
+            b.delete(i).unwrap();
+        }
+        drop(b);
+        let mut b: BTree = BTree::new(2, file_name, true, 100, false).unwrap();
+        assert_eq!(1, b.get_size());
+        assert_eq!(0, b.get_height());
+        assert_eq!(1, b.get_number_of_nodes());
+        assert_eq!(vec![9], b.get_sorted_key_array().unwrap());
+        delete_file(file_name);
+    }
+
+    /// Opening a file with a different degree than it was created with is an error, instead of misreading nodes.
+    #[test]
+    fn test_reopen_degree_mismatch() {
+        let file_name = "test_reopen_degree_mismatch.tmp";
+        delete_file(file_name);
+        drop(btree(3, file_name));
+        let result: Result<BTree> = BTree::new(2, file_name, true, 100, false);
+        assert!(matches!(result, Err(Error::DegreeMismatch { expected: 2, found: 3 })));
+        delete_file(file_name);
+    }
+
+    /// Opening a file that isn't a BTree file returns an error.
+    #[test]
+    fn test_open_empty_file() {
+        let file_name = "test_open_empty_file.tmp";
+        delete_file(file_name);
+        std::fs::File::create(file_name).unwrap();
+        let result: Result<BTree> = BTree::new(0, file_name, true, 100, false);
+        assert!(matches!(result, Err(Error::NotBTreeFile)));
+        delete_file(file_name);
+    }
+
+    /// Opening a file with a different key or value type than it was created with is an error.
+    #[test]
+    fn test_reopen_encoding_mismatch() {
+        let file_name = "test_reopen_encoding_mismatch.tmp";
+        delete_file(file_name);
+        drop(btree(2, file_name));
+        let result: Result<BTree<u128, u64>> = BTree::new(0, file_name, true, 100, false);
+        assert!(matches!(result, Err(Error::EncodingMismatch { expected: (16, 8), found: (8, 8) })));
+        delete_file(file_name);
+    }
+
+    /// Degree 0 picks the largest degree for the page size, and the page size is kept when reopening.
+    #[test]
+    fn test_open_page_size() {
+        let file_name = "test_open_page_size.tmp";
+        delete_file(file_name);
+        let options = BTreeOptions { page_size: 512, truncate_file: true, ..Default::default() };
+        let mut b: BTree = BTree::open(file_name, options).unwrap();
+        assert_eq!(10, b.get_degree());
+        for i in 0..500 {
+            b.insert(i).unwrap();
+        }
+        assert_eq!(b.get_number_of_nodes(), validate_btree_structure(&mut b));
+        drop(b);
+        let mut b: BTree = BTree::open(file_name, BTreeOptions::default()).unwrap();
+        assert_eq!(512, b.get_page_size());
+        assert_eq!(10, b.get_degree());
+        assert_eq!(b.get_number_of_nodes(), validate_btree_structure(&mut b));
+        assert_eq!(500, b.iter().count());
+        delete_file(file_name);
+    }
 }
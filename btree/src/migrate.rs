@@ -0,0 +1,285 @@
+use std::fs::{File, rename};
+use std::io::{Read, Seek, SeekFrom};
+use crate::btree_node::TreeObject;
+use crate::encoding::{Key, Value};
+use crate::error::{Error, Result};
+use crate::options::BTreeOptions;
+use crate::pager::{MAGIC, VERSION};
+use crate::BTree;
+
+/// Version given to files written before the header was versioned. Their header is only the root offset
+/// and degree as u32s, followed by nodes holding u64 keys and values, with u32 node offsets.
+const ORIGINAL_VERSION: u32 = 0;
+/// Size of the header before it was versioned.
+const ORIGINAL_HEADER_SIZE: usize = 8;
+/// Bytes taken up by each node offset in the original layout.
+const ORIGINAL_OFFSET_WIDTH: usize = 4;
+/// Bytes at the start of each original node before its keys, its offset, the is leaf flag, number of keys
+/// and number of children.
+const ORIGINAL_NODE_FIELDS_SIZE: usize = 13;
+/// Encoded length of the keys and values the original layout stores.
+const ORIGINAL_WIDTHS: (u32, u32) = (8, 8);
+
+/// Bring a BTree file written in the original layout, from before the header was versioned, up to the current one.
+/// Every key is read from the old file in order and inserted into a new BTree laid out with options,
+/// which then replaces the old file. A degree of 0 picks the optimal degree for the current layout.
+/// The original layout could hold the same key more than once, combine is given the value already copied
+/// for a key, if any, and the value being copied, and returns the value to keep. The k-mer counter adds
+/// the frequencies together, `|stored, value| stored.map_or(value, |stored| stored + value)`.
+/// Returns the version the file was written with, files already at the current version are left as they are.
+/// Original files don't store their sequence length, the migrated BTree's is 0 until it's set.
+pub fn migrate<K: Key, V: Value, F: FnMut(Option<V>, V) -> V>(file_name: &str, options: BTreeOptions, mut combine: F) -> Result<u32> {
+    let mut original = match OriginalFile::open(file_name)? {
+        Some(original) => original,
+        None => return Ok(VERSION),
+    };
+    let widths = (K::encoded_len() as u32, V::encoded_len() as u32);
+    if widths != ORIGINAL_WIDTHS {
+        return Err(Error::EncodingMismatch { expected: widths, found: ORIGINAL_WIDTHS });
+    }
+    let migrated_file = format!("{file_name}.migrate");
+    let mut btree: BTree<K, V> = BTree::open(&migrated_file, BTreeOptions { truncate_file: true, ..options })?;
+    let root_offset = original.root_offset;
+    original.copy_keys(root_offset, &mut btree, &mut combine)?;
+    // Everything has to be written before the old file is replaced, dropping the BTree would only log errors
+    btree.close()?;
+    rename(&migrated_file, file_name)?;
+    Ok(ORIGINAL_VERSION)
+}
+
+/// Check whether file is in the original layout, without magic bytes but with a header pointing at a node.
+/// Only the start of the file and the root node's offset are read.
+pub(crate) fn is_original(file: &mut File) -> Result<bool> {
+    Ok(original_header(file)?.is_some())
+}
+
+/// Root offset and degree of a file in the original layout, None if it's in any other layout.
+/// Without magic bytes, a file is only taken to be in the original layout if its degree is at least 2
+/// and the root offset points past the header at a node that holds the same offset.
+fn original_header(file: &mut File) -> Result<Option<(u64, u32)>> {
+    let mut buf = [0u8; ORIGINAL_HEADER_SIZE];
+    file.seek(SeekFrom::Start(0))?;
+    match file.read_exact(&mut buf) {
+        Ok(()) => {},
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    if buf == MAGIC {
+        return Ok(None);
+    }
+    let root_offset = read_offset(&buf, 0);
+    let degree = read_offset(&buf, 4) as u32;
+    if degree < 2 || root_offset < ORIGINAL_HEADER_SIZE as u64 {
+        return Ok(None);
+    }
+    let mut found_offset = [0u8; ORIGINAL_OFFSET_WIDTH];
+    file.seek(SeekFrom::Start(root_offset))?;
+    match file.read_exact(&mut found_offset) {
+        Ok(()) if read_offset(&found_offset, 0) == root_offset => Ok(Some((root_offset, degree))),
+        Ok(()) => Ok(None),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Node read from a file in the original layout.
+struct OriginalNode<K, V> {
+    keys: Vec<TreeObject<K, V>>,
+    children: Vec<u64>,
+}
+
+/// BTree file written in the original layout.
+struct OriginalFile {
+    file: File,
+    degree: u32,
+    root_offset: u64,
+}
+
+impl OriginalFile {
+    /// Open file and read its header, None if the file is already at the current version.
+    /// Returns NotBTreeFile if it's in neither layout.
+    fn open(file_name: &str) -> Result<Option<OriginalFile>> {
+        let mut file = File::open(file_name)?;
+        if let Some((root_offset, degree)) = original_header(&mut file)? {
+            return Ok(Some(OriginalFile { file, degree, root_offset }));
+        }
+        let mut buf = [0u8; 12];
+        file.seek(SeekFrom::Start(0))?;
+        match file.read_exact(&mut buf) {
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Err(Error::NotBTreeFile),
+            result => result?,
+        }
+        if buf[..8] != MAGIC {
+            return Err(Error::NotBTreeFile);
+        }
+        let version = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+        if version != VERSION {
+            return Err(Error::VersionMismatch { expected: VERSION, found: version });
+        }
+        Ok(None)
+    }
+
+    /// Insert every key in the subtree at offset into btree, smallest first, combining the values of keys
+    /// held more than once.
+    fn copy_keys<K: Key, V: Value, F: FnMut(Option<V>, V) -> V>(&mut self, offset: u64, btree: &mut BTree<K, V>, combine: &mut F) -> Result<()> {
+        let OriginalNode { keys, children } = self.read_node::<K, V>(offset)?;
+        for (i, obj) in keys.iter().enumerate() {
+            if let Some(&child_offset) = children.get(i) {
+                self.copy_keys(child_offset, btree, combine)?;
+            }
+            btree.upsert_with(obj.key, |stored| combine(stored, obj.value))?;
+        }
+        if let Some(&child_offset) = children.get(keys.len()) {
+            self.copy_keys(child_offset, btree, combine)?;
+        }
+        Ok(())
+    }
+
+    /// Read the keys and child offsets of the node at offset.
+    fn read_node<K: Key, V: Value>(&mut self, offset: u64) -> Result<OriginalNode<K, V>> {
+        let key_len = K::encoded_len();
+        let entry_len = key_len + V::encoded_len();
+        let max_keys = 2 * self.degree - 1;
+        let width = ORIGINAL_OFFSET_WIDTH;
+        let children_start = ORIGINAL_NODE_FIELDS_SIZE + entry_len * max_keys as usize;
+        let mut buf = vec![0u8; children_start + width * 2 * self.degree as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut buf)?;
+        let field = |i: usize| u32::from_be_bytes(buf[width + i..width + i + 4].try_into().unwrap());
+        let corrupt = |expected: u64, found: u64| Error::Corrupt { offset, expected, found };
+        let found_offset = read_offset(&buf, 0);
+        if found_offset != offset {
+            return Err(corrupt(offset, found_offset));
+        }
+        let number_of_keys = field(1);
+        if number_of_keys > max_keys {
+            return Err(corrupt(max_keys as u64, number_of_keys as u64));
+        }
+        let number_of_children = field(5);
+        if number_of_children > 2 * self.degree {
+            return Err(corrupt(2 * self.degree as u64, number_of_children as u64));
+        }
+        let keys = buf[ORIGINAL_NODE_FIELDS_SIZE..].chunks_exact(entry_len)
+            .take(number_of_keys as usize)
+            .map(|entry| TreeObject { key: K::decode(&entry[..key_len]), value: V::decode(&entry[key_len..]) })
+            .collect();
+        let children = (0..number_of_children as usize)
+            .map(|i| read_offset(&buf, children_start + i * width))
+            .collect();
+        Ok(OriginalNode { keys, children })
+    }
+}
+
+/// Big endian u32 node offset starting at byte i of buf.
+fn read_offset(buf: &[u8], i: usize) -> u64 {
+    u32::from_be_bytes(buf[i..i + ORIGINAL_OFFSET_WIDTH].try_into().unwrap()) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    // Helper function, delete files for test cleanup
+    fn delete_file(file: &str){
+        std::fs::remove_file(file).ok();
+    }
+
+    /// Frequencies of keys held more than once are added together, as the k-mer counter does.
+    fn add_frequencies(stored: Option<u64>, value: u64) -> u64 {
+        stored.map_or(value, |stored| stored + value)
+    }
+
+    /// Write a degree 2 BTree in the original layout, with the given keys in the root and each of its
+    /// leaves, stored with their frequency as key / 10.
+    fn write_original_file(file_name: &str, root: &[u64], leaves: &[&[u64]]) {
+        let node_size = (ORIGINAL_NODE_FIELDS_SIZE + 16 * 3 + ORIGINAL_OFFSET_WIDTH * 4) as u64;
+        let offsets: Vec<u64> = (0..=leaves.len() as u64).map(|i| ORIGINAL_HEADER_SIZE as u64 + i * node_size).collect();
+        let mut nodes: Vec<(&[u64], &[u64])> = vec![(root, &offsets[1..])];
+        nodes.extend(leaves.iter().map(|&leaf| (leaf, &[] as &[u64])));
+        let mut buf = vec![0u8; (offsets[leaves.len()] + node_size) as usize];
+        // Only the root offset and degree
+        buf[0..4].copy_from_slice(&(offsets[0] as u32).to_be_bytes());
+        buf[4..8].copy_from_slice(&2u32.to_be_bytes());
+        for (&offset, (keys, children)) in offsets.iter().zip(nodes) {
+            let node = &mut buf[offset as usize..];
+            node[0..4].copy_from_slice(&(offset as u32).to_be_bytes());
+            node[4] = children.is_empty() as u8;
+            node[5..9].copy_from_slice(&(keys.len() as u32).to_be_bytes());
+            node[9..13].copy_from_slice(&(children.len() as u32).to_be_bytes());
+            for (i, key) in keys.iter().enumerate() {
+                let entry = &mut node[ORIGINAL_NODE_FIELDS_SIZE + i * 16..];
+                entry[..8].copy_from_slice(&key.to_be_bytes());
+                entry[8..16].copy_from_slice(&(key / 10).to_be_bytes());
+            }
+            for (i, &child) in children.iter().enumerate() {
+                let start = ORIGINAL_NODE_FIELDS_SIZE + 16 * 3 + i * ORIGINAL_OFFSET_WIDTH;
+                node[start..start + 4].copy_from_slice(&(child as u32).to_be_bytes());
+            }
+        }
+        File::create(file_name).unwrap().write_all(&buf).unwrap();
+    }
+
+    /// Files from before the header had magic bytes, which don't store their sequence length.
+    #[test]
+    fn test_migrate_original_version() {
+        let file_name = "test_migrate_original_version.tmp";
+        delete_file(file_name);
+        write_original_file(file_name, &[20], &[&[10], &[30, 40]]);
+        let result: Result<BTree> = BTree::new(0, file_name, true, 10, false);
+        assert!(matches!(result, Err(Error::NeedsMigration)));
+        assert_eq!(0, migrate::<u64, u64, _>(file_name, BTreeOptions::default(), add_frequencies).unwrap());
+        let mut b: BTree = BTree::new(0, file_name, true, 10, false).unwrap();
+        assert_eq!(0, b.get_sequence_length());
+        assert_eq!(4, b.get_size());
+        let keys: Vec<(u64, u64)> = b.iter().map(|obj| obj.map(|obj| (obj.key, obj.value)).unwrap()).collect();
+        assert_eq!(vec![(10, 1), (20, 2), (30, 3), (40, 4)], keys);
+        assert!(!std::path::Path::new(&format!("{file_name}.migrate")).exists());
+        drop(b);
+        // Anything else without magic bytes still isn't a BTree file
+        File::create(file_name).unwrap().write_all(&[7u8; 256]).unwrap();
+        let result = migrate::<u64, u64, _>(file_name, BTreeOptions::default(), add_frequencies);
+        assert!(matches!(result, Err(Error::NotBTreeFile)));
+        let result: Result<BTree> = BTree::new(0, file_name, true, 10, false);
+        assert!(matches!(result, Err(Error::NotBTreeFile)));
+        delete_file(file_name);
+    }
+
+    /// The original insert could split a node holding a key and then add the same key again to a leaf,
+    /// so a key can be held more than once, each copy with part of its frequency.
+    #[test]
+    fn test_migrate_duplicate_keys() {
+        let file_name = "test_migrate_duplicate_keys.tmp";
+        delete_file(file_name);
+        write_original_file(file_name, &[20, 30], &[&[10, 20], &[30], &[30, 40]]);
+        assert_eq!(0, migrate::<u64, u64, _>(file_name, BTreeOptions::default(), add_frequencies).unwrap());
+        let mut b: BTree = BTree::new(0, file_name, true, 10, false).unwrap();
+        assert_eq!(4, b.get_size());
+        let keys: Vec<(u64, u64)> = b.iter().map(|obj| obj.map(|obj| (obj.key, obj.value)).unwrap()).collect();
+        assert_eq!(vec![(10, 1), (20, 4), (30, 9), (40, 4)], keys);
+        delete_file(file_name);
+    }
+
+    #[test]
+    fn test_migrate_current_version() {
+        let file_name = "test_migrate_current_version.tmp";
+        delete_file(file_name);
+        let mut b: BTree = BTree::new(2, file_name, true, 10, true).unwrap();
+        b.insert(42).unwrap();
+        drop(b);
+        let before = std::fs::read(file_name).unwrap();
+        assert_eq!(VERSION, migrate::<u64, u64, _>(file_name, BTreeOptions::default(), add_frequencies).unwrap());
+        assert_eq!(before, std::fs::read(file_name).unwrap());
+        delete_file(file_name);
+    }
+
+    #[test]
+    fn test_migrate_encoding_mismatch() {
+        let file_name = "test_migrate_encoding_mismatch.tmp";
+        delete_file(file_name);
+        write_original_file(file_name, &[20], &[&[10], &[30, 40]]);
+        let result = migrate::<u128, u64, _>(file_name, BTreeOptions::default(), |_, value| value);
+        assert!(matches!(result, Err(Error::EncodingMismatch { expected: (16, 8), found: (8, 8) })));
+        delete_file(file_name);
+    }
+}
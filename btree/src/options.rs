@@ -0,0 +1,79 @@
+use crate::cache_policy::CachePolicy;
+use crate::pager::DEFAULT_PAGE_SIZE;
+
+/// Options for creating or opening a BTree file with BTree::open.
+/// BTree files that already exist keep the degree and page size they were created with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BTreeOptions {
+    /// Degree of the BTree, 0 picks the largest degree whose nodes fit in one page.
+    /// When opening an existing file any degree other than 0 has to match the file's.
+    pub degree: u32,
+    /// Size in bytes of the pages nodes are aligned to, usually the disk block size.
+    pub page_size: u32,
+    /// Hold recently used nodes in memory.
+    pub use_cache: bool,
+    /// Number of nodes the cache holds.
+    pub cache_size: u32,
+    /// Memory in bytes the cache can use, instead of cache_size. The number of nodes it holds is worked out
+    /// from the memory a full node and the pool's bookkeeping for it take up, so the same budget suits any degree.
+    pub cache_memory: Option<u64>,
+    /// How the cache picks which node to evict when it's full.
+    pub cache_policy: CachePolicy,
+    /// Delete an existing file and start an empty BTree, instead of opening the BTree stored in it.
+    pub truncate_file: bool,
+    /// Only read the BTree, which any number of read only BTrees can do at once. Changing it returns ReadOnly,
+    /// and the file has to exist already.
+    pub read_only: bool,
+}
+
+impl Default for BTreeOptions {
+    fn default() -> Self {
+        BTreeOptions {
+            degree: 0,
+            page_size: DEFAULT_PAGE_SIZE,
+            use_cache: true,
+            cache_size: 100,
+            cache_memory: None,
+            cache_policy: CachePolicy::Lru,
+            truncate_file: false,
+            read_only: false,
+        }
+    }
+}
+
+/// Parse a size in bytes, a whole number optionally followed by a unit, such as 4096, 64KiB, 512MiB or 2GB.
+/// KiB, MiB and GiB are powers of 1024, KB, MB and GB powers of 1000, and case is ignored.
+pub fn parse_memory_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let digits = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+    let number: u64 = size[..digits].parse().map_err(|_| format!("Memory size {size} has to start with a whole number"))?;
+    let multiplier: u64 = match size[digits..].trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000 * 1000,
+        "gb" => 1000 * 1000 * 1000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        unit => return Err(format!("Unknown unit {unit} in memory size {size}, expected B, KB, MB, GB, KiB, MiB or GiB")),
+    };
+    number.checked_mul(multiplier).ok_or_else(|| format!("Memory size {size} is too large"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_memory_size() {
+        assert_eq!(Ok(4096), parse_memory_size("4096"));
+        assert_eq!(Ok(512 << 20), parse_memory_size("512MiB"));
+        assert_eq!(Ok(2_000_000_000), parse_memory_size("2 gb"));
+        assert_eq!(Ok(64 * 1024), parse_memory_size("64KiB"));
+        assert_eq!(Ok(10), parse_memory_size("10B"));
+        assert!(parse_memory_size("MiB").is_err());
+        assert!(parse_memory_size("12 parsecs").is_err());
+        assert!(parse_memory_size("-1").is_err());
+        assert!(parse_memory_size("100000000000GiB").is_err());
+    }
+}
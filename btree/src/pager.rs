@@ -1,205 +1,495 @@
-use std::fs::{File, remove_file};
+use std::fs::{File, TryLockError};
 use std::path::Path;
 use std::fs::OpenOptions;
-use std::io::{Read, Seek, Write};
+use std::io::{ErrorKind, Read, Seek, Write};
 use std::io::SeekFrom;
-use std::io::{BufWriter, BufReader};
 use crate::btree_node::Node;
+use crate::checksum::crc32c;
+use crate::encoding::{Key, Value};
+use crate::error::{Error, Result};
 use crate::TreeObject;
+use crate::migrate;
+use crate::wal::{Wal, wal_path};
 
 /// Represents numeric amount of bytes in sequence.
 type Bytes = u32;
+/// Byte offset of a node within the file.
+type Offset = u64;
 
-const _DISK_BLOCK_SIZE: Bytes = 4096;
-pub const STARTING_OFFSET: Bytes = 8;
+/// Page size used unless another is configured, a common disk block size.
+pub const DEFAULT_PAGE_SIZE: Bytes = 4096;
+/// Bytes every BTree file starts with, identifying it as one.
+pub(crate) const MAGIC: [u8; 8] = *b"GBBTREE\0";
+/// Version of the file layout, bumped whenever the header or node layout changes.
+/// Files from before the header was versioned can be brought up to date with migrate::migrate.
+pub const VERSION: u32 = 1;
+/// Size of the header block at the start of file, larger than the fields in it to leave room for new ones.
+/// The header has the whole first page to itself, so nodes start on a page boundary.
+pub const HEADER_SIZE: Bytes = 128;
+/// Bytes at the start of each node before its keys, the offset, is leaf flag, number of keys, number of children
+/// and checksum.
+const NODE_HEADER_SIZE: Bytes = 21;
+/// Position of the CRC32C checksum within a node, covering the whole node with the checksum zeroed.
+const NODE_CHECKSUM: usize = 17;
+/// Position of the CRC32C checksum within the header, covering the whole header with the checksum zeroed.
+const HEADER_CHECKSUM: usize = 68;
+/// Is leaf byte of a page on the free list instead of a node, followed by the offset of the next free page.
+const FREE_PAGE: u8 = 2;
+/// Bytes taken up by each child offset.
+const OFFSET_SIZE: Bytes = 8;
+
+/// Size in bytes of a node of the given degree, with keys and values taking up entry_len bytes together.
+pub fn node_size(degree: u32, entry_len: u32) -> Bytes {
+    NODE_HEADER_SIZE + entry_len * (2 * degree - 1) + OFFSET_SIZE * 2 * degree
+}
+
+/// Largest degree whose nodes fit in one page, with keys and values taking up entry_len bytes together.
+/// Pages too small for a degree 2 node still get degree 2, with each node taking up more than one page.
+pub fn optimal_degree(page_size: Bytes, entry_len: u32) -> u32 {
+    // Each degree adds 2 keys and values, and 2 child offsets
+    let degree = (page_size + entry_len).saturating_sub(NODE_HEADER_SIZE) / (2 * (entry_len + OFFSET_SIZE));
+    degree.max(2)
+}
+
+/// Big endian u32 starting at byte i of buf.
+fn read_u32(buf: &[u8], i: usize) -> u32 {
+    u32::from_be_bytes(buf[i..i + 4].try_into().unwrap())
+}
+
+/// Big endian u64 starting at byte i of buf.
+fn read_u64(buf: &[u8], i: usize) -> u64 {
+    u64::from_be_bytes(buf[i..i + 8].try_into().unwrap())
+}
+
+/// Check the CRC32C checksum stored at position within buf, the header or node at offset, matches the rest of buf.
+/// The stored checksum is zeroed, as it was when the checksum was worked out.
+fn verify_checksum(buf: &mut [u8], position: usize, offset: Offset) -> Result<()> {
+    let stored = read_u32(buf, position);
+    buf[position..position + 4].fill(0);
+    let computed = crc32c(buf);
+    if stored != computed {
+        return Err(Error::ChecksumMismatch { offset, expected: stored, found: computed });
+    }
+    Ok(())
+}
+
+/// BTree metadata stored in the header at the start of file, describing how the file was created
+/// and everything needed to carry on with an existing BTree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    /// Size of the pages nodes are aligned to.
+    pub page_size: u32,
+    pub degree: u32,
+    /// Length of the gene sequences stored, 0 if not set.
+    pub sequence_length: u32,
+    /// Encoded length of each key in bytes.
+    pub key_width: u32,
+    /// Encoded length of each value in bytes.
+    pub value_width: u32,
+    pub root_offset: Offset,
+    pub number_of_nodes: u64,
+    pub number_of_keys: u64,
+    pub height: u32,
+    /// Offset the next new node is written at.
+    pub file_cursor: Offset,
+    /// Offset of the first page on the free list, 0 if it's empty.
+    pub free_list: Offset,
+    /// Number of pages on the free list.
+    pub free_pages: u64,
+}
 
 /// Pager Struct representing reading and writing Btree's gene sequence to file.
 /// Pager is specifically designed for Node structs.
+/// Every node is padded out to a whole number of pages and starts on a page boundary, so a node
+/// that fits in a page is read and written as exactly one page.
+/// With a write-ahead log, nodes are logged instead of written to file until the header is written.
+/// Pages freed by nodes that are no longer used are kept on a free list, each free page holding the offset
+/// of the next one, and handed out again before the file grows.
 pub struct Pager {
-    pub file_cursor: Bytes,
-    buf_write: BufWriter<File>,
-    buf_read: BufReader<File>,
+    pub file_cursor: Offset,
+    /// Offset of the first free page, 0 if there are none
+    pub free_list: Offset,
+    pub free_pages: u64,
+    file: File,
     degree: u32,
+    page_size: Bytes,
+    wal: Option<Wal>,
 }
 
 impl Pager {
     /// Pager Constructor
-    pub fn new(file_name: &str, degree: u32) -> Result<Pager, std::io::Error> {
-        let path = Path::new(file_name);
-        // Delete file if truncate_file is set to true, cause rerunning tests with non deleted files results in incorrect outputs
-        let file_cursor = STARTING_OFFSET;
+    pub fn new(file_name: &str, degree: u32, page_size: Bytes) -> Result<Pager> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(path).unwrap();
-        let buf_write = BufWriter::new(file.try_clone().unwrap());
-        let buf_read = BufReader::new(file.try_clone().unwrap());
-        Ok(Pager { file_cursor, buf_write, buf_read, degree })
+            .truncate(false)
+            .open(Path::new(file_name))?;
+        Pager::with_file(file, degree, page_size)
+    }
+
+    /// Open file_name to read nodes straight from file, without asking for write access to it. The file is
+    /// locked with a shared lock, so any number of read only pagers can have it open at once, but none while
+    /// it's open for writing. A batch left committed in the write-ahead log by a BTree that didn't close is
+    /// still copied to file first, under an exclusive lock, which is the only time the file is opened for writing.
+    /// Returns FileInUse if the file is already open for writing, and RecoveryNeedsWrite if there's a batch
+    /// to copy but the file or its log can't be written.
+    pub fn open_read_only(file_name: &str, degree: u32, page_size: Bytes) -> Result<Pager> {
+        let file = OpenOptions::new().read(true).open(Path::new(file_name))?;
+        let path = wal_path(file_name);
+        if std::fs::metadata(&path).is_ok_and(|log| log.len() > 0) {
+            let denied = |err: std::io::Error| match err.kind() {
+                ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => Error::RecoveryNeedsWrite,
+                _ => err.into(),
+            };
+            let data = OpenOptions::new().read(true).write(true).open(Path::new(file_name)).map_err(denied)?;
+            let mut writer = Pager::with_file(data, degree, page_size)?;
+            writer.lock(false)?;
+            match Wal::open(&path, &mut writer.file) {
+                Err(Error::Io(err)) => return Err(denied(err)),
+                result => drop(result?),
+            }
+        }
+        let mut pager = Pager::with_file(file, degree, page_size)?;
+        pager.lock(true)?;
+        Ok(pager)
+    }
+
+    /// Pager reading and writing the already open file.
+    fn with_file(file: File, degree: u32, page_size: Bytes) -> Result<Pager> {
+        if page_size < HEADER_SIZE {
+            return Err(Error::InvalidPageSize(page_size));
+        }
+        // Nodes start on the page after the header
+        let file_cursor = page_size as Offset;
+        Ok(Pager { file_cursor, free_list: 0, free_pages: 0, file, degree, page_size, wal: None })
+    }
+
+    /// Write nodes and the header through a write-ahead log kept alongside file_name, see Wal.
+    /// When recovering, a batch committed to the log before the BTree was last closed is copied to file,
+    /// otherwise whatever is in the log is thrown away.
+    /// The file is locked until the pager is dropped, returning FileInUse if it's already locked.
+    pub fn open_wal(&mut self, file_name: &str, recover: bool) -> Result<()> {
+        self.lock(false)?;
+        let path = wal_path(file_name);
+        self.wal = Some(if recover { Wal::open(&path, &mut self.file)? } else { Wal::create(&path)? });
+        Ok(())
+    }
+
+    /// Stop logging writes, removing the write-ahead log if everything in it has been committed.
+    /// The file stays locked, and anything written after is written straight to it.
+    pub fn close_wal(&mut self) {
+        self.wal = None;
+    }
+
+    /// Lock the file until the pager is dropped, shared or exclusive, returning FileInUse if it's already locked.
+    fn lock(&mut self, shared: bool) -> Result<()> {
+        let locked = if shared { self.file.try_lock_shared() } else { self.file.try_lock() };
+        match locked {
+            Err(TryLockError::WouldBlock) => Err(Error::FileInUse),
+            Err(TryLockError::Error(err)) => Err(err.into()),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Nodes have been written to the write-ahead log since the header was last written.
+    pub fn uncommitted(&self) -> bool {
+        self.wal.as_ref().is_some_and(Wal::uncommitted)
+    }
+
+    /// Write btree metadata to the header at start of file, first 128 bytes.
+    /// Header is the magic bytes and version, followed by the metadata fields in the order they're
+    /// declared in, big endian with u64 offsets and counts and u32 everything else. The header's checksum comes
+    /// after the file cursor, before the free list, and the rest of the header is zeroed.
+    pub fn write_metadata(&mut self, metadata: &Metadata) -> Result<()> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE as usize);
+        buf.extend_from_slice(&MAGIC);
+        for field in [VERSION, metadata.page_size, metadata.degree, metadata.sequence_length, metadata.key_width, metadata.value_width] {
+            buf.extend_from_slice(&field.to_be_bytes());
+        }
+        buf.extend_from_slice(&metadata.root_offset.to_be_bytes());
+        buf.extend_from_slice(&metadata.number_of_nodes.to_be_bytes());
+        buf.extend_from_slice(&metadata.number_of_keys.to_be_bytes());
+        buf.extend_from_slice(&metadata.height.to_be_bytes());
+        buf.extend_from_slice(&metadata.file_cursor.to_be_bytes());
+        // Checksum, filled in once the rest of the header is
+        buf.extend_from_slice(&[0u8; 4]);
+        buf.extend_from_slice(&metadata.free_list.to_be_bytes());
+        buf.extend_from_slice(&metadata.free_pages.to_be_bytes());
+        buf.resize(HEADER_SIZE as usize, 0);
+        let checksum = crc32c(&buf);
+        buf[HEADER_CHECKSUM..HEADER_CHECKSUM + 4].copy_from_slice(&checksum.to_be_bytes());
+        // Writing the header commits the nodes logged since it was last written
+        if let Some(wal) = &mut self.wal {
+            return wal.commit(&buf, &mut self.file);
+        }
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Read metadata from the header at start of file.
+    /// Returns an error if the file doesn't start with a BTree header, was written with a different version,
+    /// or the header has changed since it was written. Files from before the header was versioned return NeedsMigration.
+    pub fn read_metadata(&mut self) -> Result<Metadata> {
+        let mut buf = [0u8; HEADER_SIZE as usize];
+        self.file.seek(SeekFrom::Start(0))?;
+        let read = match self.file.read_exact(&mut buf) {
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => false,
+            result => result.map(|()| true)?,
+        };
+        if !read || buf[..8] != MAGIC {
+            // Files from before the header was versioned can still be migrated
+            if migrate::is_original(&mut self.file)? {
+                return Err(Error::NeedsMigration);
+            }
+            return Err(Error::NotBTreeFile);
+        }
+        let version = read_u32(&buf, 8);
+        if version != VERSION {
+            return Err(Error::VersionMismatch { expected: VERSION, found: version });
+        }
+        verify_checksum(&mut buf, HEADER_CHECKSUM, 0)?;
+        // Nodes are laid out from these, so they're checked before anything is read with them
+        let page_size = read_u32(&buf, 12);
+        if page_size < HEADER_SIZE {
+            return Err(Error::InvalidPageSize(page_size));
+        }
+        let degree = read_u32(&buf, 16);
+        if degree < 2 {
+            return Err(Error::InvalidDegree(degree));
+        }
+        Ok(Metadata {
+            page_size,
+            degree,
+            sequence_length: read_u32(&buf, 20),
+            key_width: read_u32(&buf, 24),
+            value_width: read_u32(&buf, 28),
+            root_offset: read_u64(&buf, 32),
+            number_of_nodes: read_u64(&buf, 40),
+            number_of_keys: read_u64(&buf, 48),
+            height: read_u32(&buf, 56),
+            file_cursor: read_u64(&buf, 60),
+            free_list: read_u64(&buf, 72),
+            free_pages: read_u64(&buf, 80),
+        })
     }
 
-    // TODO Need to return an offset, set the offset counter correctly.
-    /// Write btree metadata to start of file, first 8 bytes.
-    /// Meta data is u32 root offset, followed by u32 degree
-    pub fn write_metadata(&mut self, mut root_offset: u32, degree: u32) {
-        if root_offset == 0 {
-            root_offset = STARTING_OFFSET;
-            self.file_cursor += STARTING_OFFSET;
-        }
-        self.buf_write.seek(SeekFrom::Start(0)).unwrap();
-        self.buf_write.write_all(&root_offset.to_be_bytes()).unwrap();
-        self.buf_write.write_all(&degree.to_be_bytes()).unwrap();
-        self.buf_write.flush().unwrap();
+    /// Set the degree and page size used for laying out nodes, for opening a file written with different ones.
+    pub fn set_layout(&mut self, degree: u32, page_size: Bytes) {
+        self.degree = degree;
+        self.page_size = page_size;
     }
 
-    /// Read metadata at start of file, throwing error if byte sequence not found.
-    /// Return u32 offset followed by u32 degree in tuple
-    pub fn read_metadata(&mut self) -> Result<(u32, u32), std::io::Error >  {
-        let mut buf = [0u8; 4];
-        self.buf_read.seek(SeekFrom::Start(0))?;
-        // Root Offset
-        self.buf_read.read_exact(&mut buf)?;
-        let root_offset = u32::from_be_bytes(buf);
-        // degree
-        self.buf_read.read_exact(&mut buf)?;
-        let degree = u32::from_be_bytes(buf);
-        Ok((root_offset, degree))
+    /// Size of the pages nodes are aligned to.
+    pub fn page_size(&self) -> Bytes {
+        self.page_size
+    }
+
+    /// Wait for everything written to the file to reach the disk.
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    /// Reserve space for a new node, returning the offset to write it at. The first page on the free list
+    /// is reused if there is one, otherwise the node goes at the end of file.
+    /// Nodes can be written any time after, while the offsets of every other new node carry on past it.
+    pub fn allocate<K: Key, V: Value>(&mut self) -> Result<Offset> {
+        if self.free_list != 0 {
+            let offset = self.free_list;
+            self.free_list = self.read_free_page::<K, V>(offset)?;
+            self.free_pages -= 1;
+            return Ok(offset);
+        }
+        let offset = self.file_cursor;
+        self.file_cursor += self.slot_size::<K, V>() as Offset;
+        Ok(offset)
+    }
+
+    /// Put the page of a node that is no longer used on the free list, for allocate to hand out again.
+    /// The page is overwritten with the offset of the next free page, so the free list lasts
+    /// with only its first page's offset kept in the header.
+    pub fn free<K: Key, V: Value>(&mut self, offset: Offset) -> Result<()> {
+        let mut buf = vec![0u8; self.slot_size::<K, V>() as usize];
+        buf[0..8].copy_from_slice(&offset.to_be_bytes());
+        buf[8] = FREE_PAGE;
+        buf[9..17].copy_from_slice(&self.free_list.to_be_bytes());
+        let node_len = node_size(self.degree, (K::encoded_len() + V::encoded_len()) as u32) as usize;
+        let checksum = crc32c(&buf[..node_len]);
+        buf[NODE_CHECKSUM..NODE_CHECKSUM + 4].copy_from_slice(&checksum.to_be_bytes());
+        self.write_page(offset, &buf)?;
+        self.free_list = offset;
+        self.free_pages += 1;
+        Ok(())
+    }
+
+    /// Read the free page at offset, returning the offset of the next free page, 0 if it's the last.
+    fn read_free_page<K: Key, V: Value>(&mut self, offset: Offset) -> Result<Offset> {
+        let mut buf = vec![0u8; self.slot_size::<K, V>() as usize];
+        self.read_page(offset, &mut buf)?;
+        let found_offset = read_u64(&buf, 0);
+        if found_offset != offset {
+            return Err(Error::Corrupt { offset, expected: offset, found: found_offset });
+        }
+        let node_len = node_size(self.degree, (K::encoded_len() + V::encoded_len()) as u32) as usize;
+        verify_checksum(&mut buf[..node_len], NODE_CHECKSUM, offset)?;
+        if buf[8] != FREE_PAGE {
+            return Err(Error::Corrupt { offset, expected: FREE_PAGE as u64, found: buf[8] as u64 });
+        }
+        Ok(read_u64(&buf, 9))
+    }
+
+    /// Write the page at offset, to the write-ahead log when there is one.
+    fn write_page(&mut self, offset: Offset, buf: &[u8]) -> Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal.append(offset, buf)?,
+            None => {
+                self.file.seek(SeekFrom::Start(offset))?;
+                self.file.write_all(buf)?;
+            },
+        }
+        Ok(())
+    }
+
+    /// Read the page at offset into buf. Pages logged since the last commit are only in the write-ahead log.
+    fn read_page(&mut self, offset: Offset, buf: &mut [u8]) -> Result<()> {
+        let logged = match &mut self.wal {
+            Some(wal) => wal.read(offset, buf)?,
+            None => false,
+        };
+        if !logged {
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.read_exact(buf)?;
+        }
+        Ok(())
+    }
+
+    /// Bytes each node takes up in the file, its size rounded up to a whole number of pages.
+    pub fn slot_size<K: Key, V: Value>(&self) -> Bytes {
+        let entry_len = (K::encoded_len() + V::encoded_len()) as u32;
+        node_size(self.degree, entry_len).div_ceil(self.page_size) * self.page_size
     }
 
     /// Write BTree Node to file, goes through all parts of Node's values and writes their
     /// byte sequence to disk.  If Node doesn't have keys or child ptrs, write their max 
     /// possible ammount to give buffer between this node and next in file.
-    pub fn write(&mut self, node: &Node) {
-        // Don't move file cursor for updating existing nodes
-        let move_cursor = node.offset >= self.file_cursor;
-        // Write node to disk
+    /// Keys and values are written with their Encodable implementations.
+    /// The node is padded out to its whole slot and written in one go.
+    pub fn write<K: Key, V: Value>(&mut self, node: &Node<K, V>) -> Result<()> {
+        let key_len = K::encoded_len();
+        let entry_len = key_len + V::encoded_len();
+        let slot_size = self.slot_size::<K, V>();
+        let mut buf = vec![0u8; slot_size as usize];
         // Offset
-        self.buf_write.seek(SeekFrom::Start(node.offset as u64)).unwrap();
-        self.buf_write.write_all(&node.offset.to_be_bytes()).unwrap();
+        buf[0..8].copy_from_slice(&node.offset.to_be_bytes());
         // is Leaf Node
-        // Don't see a way to convert bool to u8, so this will do
-        if node.is_leaf() {
-            self.buf_write.write_all(&[1;1]).unwrap();
-        } else {
-            self.buf_write.write_all(&[0;1]).unwrap();
-        }
+        buf[8] = node.is_leaf() as u8;
         // Number of Keys
-        self.buf_write.write_all(&node.number_of_keys().to_be_bytes()).unwrap();
-        self.buf_write.write_all(&node.number_of_children().to_be_bytes()).unwrap();
-        // Keys
-        for i in 0..(2*self.degree-1) {
-            if i < node.keys.len() as u32 {
-                self.buf_write.write_all(&node.keys.get(i as usize).unwrap().sequence.to_be_bytes()).unwrap();
-                self.buf_write.write_all(&node.keys.get(i as usize).unwrap().frequency.to_be_bytes()).unwrap();
-            }
-            else {
-                self.buf_write.write_all(&[0;16]).unwrap();
-            }
-            if move_cursor{
-                // 2 u64s is 16 bytes (8 bits)
-                self.file_cursor += 16;
-            }
+        buf[9..13].copy_from_slice(&node.number_of_keys().to_be_bytes());
+        buf[13..17].copy_from_slice(&node.number_of_children().to_be_bytes());
+        // Checksum at 17..21 is filled in once the rest of the node is
+        // Keys, unused key slots are left zeroed
+        let keys_start = NODE_HEADER_SIZE as usize;
+        for (obj, entry) in node.keys.iter().zip(buf[keys_start..].chunks_exact_mut(entry_len)) {
+            obj.key.encode(&mut entry[..key_len]);
+            obj.value.encode(&mut entry[key_len..]);
         }
-        // Children Offsets
-        for i in 0..(2*self.degree) {
-            if i < node.children_ptrs.len() as u32 {
-                let offset = &node.children_ptrs.get(i as usize).unwrap().to_be_bytes();
-                self.buf_write.write_all(offset).unwrap();
-            }
-            else {
-                self.buf_write.write_all(&[0;4]).unwrap();
-            }
-            if move_cursor{
-                self.file_cursor += 4;
-            }
+        // Children Offsets, after every key slot
+        let children_start = keys_start + entry_len * (2 * self.degree - 1) as usize;
+        for (offset, chunk) in node.children_ptrs.iter().zip(buf[children_start..].chunks_exact_mut(OFFSET_SIZE as usize)) {
+            chunk.copy_from_slice(&offset.to_be_bytes());
         }
-
-        if move_cursor {
-            self.file_cursor += 13;
+        let node_len = node_size(self.degree, entry_len as u32) as usize;
+        let checksum = crc32c(&buf[..node_len]);
+        buf[NODE_CHECKSUM..NODE_CHECKSUM + 4].copy_from_slice(&checksum.to_be_bytes());
+        self.write_page(node.offset, &buf)?;
+        // Don't move file cursor for updating existing or allocated nodes
+        if node.offset >= self.file_cursor {
+            self.file_cursor = node.offset + slot_size as Offset;
         }
-        self.buf_write.flush().unwrap();
+        Ok(())
     }
 
     /// Read Node Struct from file, with given byte offset.
-    pub fn read(&mut self, offset: u32) -> Node {
-        let mut buf = [0u8; 4];
-        self.buf_read.seek(SeekFrom::Start(offset as u64)).unwrap();
+    /// Returns a Corrupt error if the bytes at offset don't look like the node written there,
+    /// or a ChecksumMismatch error if the node has changed since it was written.
+    pub fn read<K: Key, V: Value>(&mut self, offset: Offset) -> Result<Node<K, V>> {
+        let key_len = K::encoded_len();
+        let entry_len = key_len + V::encoded_len();
+        let mut buf = vec![0u8; self.slot_size::<K, V>() as usize];
+        self.read_page(offset, &mut buf)?;
         // Offset
-        self.buf_read.read_exact(&mut buf).unwrap();
-        let found_offset = u32::from_be_bytes(buf);
+        let found_offset = read_u64(&buf, 0);
         if found_offset != offset {
-            panic!("Found offset ({found_offset}) doesn't match given offset ({offset}). Offset misaligned.")
+            return Err(Error::Corrupt { offset, expected: offset, found: found_offset });
         }
+        let node_len = node_size(self.degree, entry_len as u32) as usize;
+        verify_checksum(&mut buf[..node_len], NODE_CHECKSUM, offset)?;
         // is Leaf Node
-        self.buf_read.read_exact(&mut buf[..1]).unwrap();
-        let is_leaf: bool = u8::from_be_bytes(buf[..1].try_into().unwrap()) == 1;
+        let is_leaf = match buf[8] {
+            0 => false,
+            1 => true,
+            found => return Err(Error::Corrupt { offset, expected: 1, found: found as u64 }),
+        };
         // Number of Keys
-        self.buf_read.read_exact(&mut buf).unwrap();
-        let number_of_keys = u32::from_be_bytes(buf);
-        // Number of Children Offsets
-        self.buf_read.read_exact(&mut buf).unwrap();
-        let number_children_offsets = u32::from_be_bytes(buf);
-        // Keys
-        let mut key_buf = [0u8; 8];
-        let mut keys: Vec<TreeObject> = Vec::new();
-        for _ in 0..number_of_keys {
-            self.buf_read.read_exact(&mut key_buf).unwrap();
-            let sequence = u64::from_be_bytes(key_buf);
-            self.buf_read.read_exact(&mut key_buf).unwrap();
-            let frequency = u64::from_be_bytes(key_buf);
-            keys.push(TreeObject {sequence, frequency});
-
+        let number_of_keys = read_u32(&buf, 9);
+        if number_of_keys > 2*self.degree-1 {
+            return Err(Error::Corrupt { offset, expected: (2*self.degree-1) as u64, found: number_of_keys as u64 });
         }
-        let _new_offset = self.buf_read.seek(SeekFrom::Current(((2*self.degree-1) as i64 - number_of_keys as i64) * 16)).unwrap();
-        // Children Offsets
-        let mut children_offsets: Vec<u32> = Vec::new();
-        for _ in 0..number_children_offsets {
-            self.buf_read.read_exact(&mut buf).unwrap();
-            children_offsets.push(u32::from_be_bytes(buf));
+        // Number of Children Offsets, none for a leaf and one more than the keys otherwise
+        let number_children_offsets = read_u32(&buf, 13);
+        let expected_children = if is_leaf { 0 } else { number_of_keys + 1 };
+        if number_children_offsets != expected_children {
+            return Err(Error::Corrupt { offset, expected: expected_children as u64, found: number_children_offsets as u64 });
         }
-        Node {keys,
+        // Only a leaf can be empty, and only when it's the root of an empty tree
+        if !is_leaf && number_of_keys == 0 {
+            return Err(Error::Corrupt { offset, expected: 1, found: 0 });
+        }
+        // Keys
+        let keys_start = NODE_HEADER_SIZE as usize;
+        let keys: Vec<TreeObject<K, V>> = buf[keys_start..].chunks_exact(entry_len)
+            .take(number_of_keys as usize)
+            .map(|entry| TreeObject { key: K::decode(&entry[..key_len]), value: V::decode(&entry[key_len..]) })
+            .collect();
+        // Children Offsets
+        let children_start = keys_start + entry_len * (2 * self.degree - 1) as usize;
+        let children_offsets: Vec<Offset> = (0..number_children_offsets as usize)
+            .map(|i| read_u64(&buf, children_start + i * OFFSET_SIZE as usize))
+            .collect();
+        Ok(Node {keys,
             number_of_keys,  // TODO Why does a node care about max keys, couln't this be only known by the btree?
             is_leaf,
             children_ptrs: children_offsets,
             offset: found_offset
-        }
-    }
-
-    /// Get root offset from metadata
-    pub fn get_root_offset(&mut self) -> Result<u32, std::io::Error> {
-        let meta = match self.read_metadata() {
-            Ok(meta)  => meta,
-            Err(e) => return Err(e),
-        };
-        Ok(meta.0)
+        })
     }
 
-    /// Return the Node Struct, by finding where it is from the metadata
-    pub fn read_root(&mut self) -> Result<Node, std::io::Error> {
-        let offset = self.get_root_offset()?;
-        Ok(self.read(offset))
-    }
-
-    /// Drop an existing btree file, and recreate the file along with its metadata and first node
-    pub fn recreate_file(&mut self, file_name: &str, degree: u32, node: &Node) -> u32 {
-        let path = Path::new(file_name);
-        if Path::new(path).exists() {
-            remove_file(path).expect("Unable to remove file.");
-        }
-        // Recreate file handler, otherwise writing into void
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path).unwrap();
-        self.buf_write = BufWriter::new(file.try_clone().unwrap());
-        self.buf_read = BufReader::new(file.try_clone().unwrap());
-
-        self.write_metadata(STARTING_OFFSET, degree);
-        //TODO Node here isn't set correctly to offset of 8, should fix - temp fix is setting it in node struct temp
-        assert!(self.file_cursor == STARTING_OFFSET);
-        self.write(node);
-        STARTING_OFFSET
+    /// Empty the btree file, and recreate its metadata and first node.
+    /// The first node is moved to the page after the header.
+    /// The file is truncated in place rather than deleted, so it keeps any lock taken on it.
+    pub fn recreate_file<K: Key, V: Value>(&mut self, node: &mut Node<K, V>) -> Result<()> {
+        self.file.set_len(0)?;
+        self.file_cursor = self.page_size as Offset;
+        self.free_list = 0;
+        self.free_pages = 0;
+        node.offset = self.file_cursor;
+        self.write(node)?;
+        self.write_metadata(&Metadata {
+            page_size: self.page_size,
+            degree: self.degree,
+            sequence_length: 0,
+            key_width: K::encoded_len() as u32,
+            value_width: V::encoded_len() as u32,
+            root_offset: node.offset,
+            number_of_nodes: 1,
+            number_of_keys: 0,
+            height: 0,
+            file_cursor: self.file_cursor,
+            free_list: 0,
+            free_pages: 0,
+        })
     }
 
 }
@@ -218,13 +508,24 @@ mod tests {
     fn test_pager_metadata() {
         let file_name = "test_pager_metadata.tmp";
         delete_file(file_name);
-        let mut pager = Pager::new(file_name, 1).unwrap();
-        let expected_root_offset = 10;
-        let expected_degree = 10;
-        pager.write_metadata(expected_root_offset, expected_degree);
-        let (actual_root_offset, actual_degree) = pager.read_metadata().unwrap();
-        assert_eq!(expected_root_offset, actual_root_offset);
-        assert_eq!(expected_degree, actual_degree);
+        let mut pager = Pager::new(file_name, 1, DEFAULT_PAGE_SIZE).unwrap();
+        let expected = Metadata {
+            page_size: DEFAULT_PAGE_SIZE,
+            degree: 10,
+            sequence_length: 31,
+            key_width: 8,
+            value_width: 8,
+            // Offsets past 4 GiB, and counts past u32::MAX
+            root_offset: 5 << 32,
+            number_of_nodes: 3 << 32,
+            number_of_keys: 5 << 32,
+            height: 2,
+            file_cursor: (6 << 32) + 4096,
+            free_list: (5 << 32) + 4096,
+            free_pages: (1 << 32) + 3,
+        };
+        pager.write_metadata(&expected).unwrap();
+        assert_eq!(expected, pager.read_metadata().unwrap());
         delete_file(file_name);
     }
 
@@ -232,10 +533,10 @@ mod tests {
         fn test_pager_write_read_1_node() {
             let file_name = "test_pager_write_read_1_node.tmp";
             delete_file(file_name);
-            let mut pager = Pager::new(file_name, 1).unwrap();
-            let expected_node = Node::new();
-            pager.write(&expected_node);
-            let actual_node = pager.read(expected_node.offset);
+            let mut pager = Pager::new(file_name, 1, DEFAULT_PAGE_SIZE).unwrap();
+            let expected_node: Node = Node::new();
+            pager.write(&expected_node).unwrap();
+            let actual_node = pager.read(expected_node.offset).unwrap();
             assert_eq!(expected_node, actual_node);
             delete_file(file_name);
         }
@@ -244,16 +545,16 @@ mod tests {
         fn test_pager_write_read_2_node() {
             let file_name = "test_pager_write_read_2_node.tmp";
             delete_file(file_name);
-            let mut pager = Pager::new(file_name, 1).unwrap();
+            let mut pager = Pager::new(file_name, 1, DEFAULT_PAGE_SIZE).unwrap();
             let mut nodes: Vec<Node> = Vec::new();
             for _ in 0..2 {
-                let mut node = Node::new();
+                let mut node: Node = Node::new();
                 node.offset = pager.file_cursor;
-                pager.write(&node);
+                pager.write(&node).unwrap();
                 nodes.push(node)
             }
             for expected_node in nodes{
-                let actual_node = pager.read(expected_node.offset);
+                let actual_node = pager.read(expected_node.offset).unwrap();
                 assert_eq!(expected_node, actual_node);
             }
             delete_file(file_name);
@@ -263,24 +564,304 @@ mod tests {
         fn test_pager_metadata_10_nodes(){
             let file_name = "test_pager_metadata_10_nodes.tmp";
             delete_file(file_name);
-            let mut pager = Pager::new(file_name, 1).unwrap();
-            pager.file_cursor = STARTING_OFFSET;
-            pager.write_metadata(8, 1);
+            let mut pager = Pager::new(file_name, 2, DEFAULT_PAGE_SIZE).unwrap();
+            pager.file_cursor = DEFAULT_PAGE_SIZE as u64;
+            let expected = Metadata {
+                page_size: DEFAULT_PAGE_SIZE,
+                degree: 2,
+                sequence_length: 0,
+                key_width: 8,
+                value_width: 8,
+                root_offset: DEFAULT_PAGE_SIZE as u64,
+                number_of_nodes: 10,
+                number_of_keys: 0,
+                height: 0,
+                file_cursor: 0,
+                free_list: 0,
+                free_pages: 0,
+            };
+            pager.write_metadata(&expected).unwrap();
             let mut nodes: Vec<Node> = Vec::new();
             for _ in 0..10 {
-                let mut node = Node::new();
+                let mut node: Node = Node::new();
                 node.offset = pager.file_cursor;
-                pager.write(&node);
+                pager.write(&node).unwrap();
                 nodes.push(node)
             }
             for expected_node in nodes{
-                let actual_node = pager.read(expected_node.offset);
+                let actual_node = pager.read(expected_node.offset).unwrap();
                 assert_eq!(expected_node, actual_node);
             }
-            let (actual_offset, actual_degree ) = pager.read_metadata().unwrap();
-            assert_eq!(8, actual_offset);
-            assert_eq!(1, actual_degree);
+            assert_eq!(expected, pager.read_metadata().unwrap());
+            delete_file(file_name);
+        }
+
+        #[test]
+        fn test_pager_write_read_generic_node() {
+            let file_name = "test_pager_write_read_generic_node.tmp";
+            delete_file(file_name);
+            let mut pager = Pager::new(file_name, 2, DEFAULT_PAGE_SIZE).unwrap();
+            let mut expected_node: Node<u128, [u32; 3]> = Node::new();
+            expected_node.keys.push(TreeObject::new(u128::MAX - 1, [1, 2, 3]));
+            expected_node.keys.push(TreeObject::new(u128::MAX, [4, 5, 6]));
+            expected_node.number_of_keys = 2;
+            pager.write(&expected_node).unwrap();
+            let actual_node: Node<u128, [u32; 3]> = pager.read(expected_node.offset).unwrap();
+            assert_eq!(expected_node, actual_node);
+            assert_eq!([4, 5, 6], actual_node.keys[1].value);
+            delete_file(file_name);
+        }
+
+        #[test]
+        fn test_pager_read_wrong_number_of_children() {
+            let file_name = "test_pager_read_wrong_number_of_children.tmp";
+            delete_file(file_name);
+            let mut pager = Pager::new(file_name, 2, DEFAULT_PAGE_SIZE).unwrap();
+            // Internal node with one child for its one key
+            let mut internal: Node = Node::new();
+            internal.offset = pager.file_cursor;
+            internal.is_leaf = false;
+            internal.keys.push(TreeObject::new(1, 1));
+            internal.number_of_keys = 1;
+            internal.children_ptrs.push(pager.file_cursor);
+            pager.write(&internal).unwrap();
+            let result: Result<Node> = pager.read(internal.offset);
+            assert!(matches!(result, Err(Error::Corrupt { offset, expected: 2, found: 1 }) if offset == internal.offset));
+            // Leaf with a child
+            internal.is_leaf = true;
+            pager.write(&internal).unwrap();
+            let result: Result<Node> = pager.read(internal.offset);
+            assert!(matches!(result, Err(Error::Corrupt { offset, expected: 0, found: 1 }) if offset == internal.offset));
+            // Internal node with no keys and its one child
+            internal.is_leaf = false;
+            internal.keys.clear();
+            internal.number_of_keys = 0;
+            pager.write(&internal).unwrap();
+            let result: Result<Node> = pager.read(internal.offset);
+            assert!(matches!(result, Err(Error::Corrupt { offset, expected: 1, found: 0 }) if offset == internal.offset));
+            delete_file(file_name);
+        }
+
+        #[test]
+        fn test_pager_read_misaligned_offset() {
+            let file_name = "test_pager_read_misaligned_offset.tmp";
+            delete_file(file_name);
+            let mut pager = Pager::new(file_name, 2, DEFAULT_PAGE_SIZE).unwrap();
+            let node: Node = Node::new();
+            pager.write(&node).unwrap();
+            // Second node so the misaligned read isn't past the end of file
+            let mut next_node: Node = Node::new();
+            next_node.offset = pager.file_cursor;
+            pager.write(&next_node).unwrap();
+            let result: Result<Node> = pager.read(node.offset + 4);
+            assert!(matches!(result, Err(Error::Corrupt { offset, .. }) if offset == node.offset + 4));
+            delete_file(file_name);
+        }
+
+        #[test]
+        fn test_pager_read_truncated_file() {
+            let file_name = "test_pager_read_truncated_file.tmp";
+            delete_file(file_name);
+            let mut pager = Pager::new(file_name, 2, DEFAULT_PAGE_SIZE).unwrap();
+            assert!(matches!(pager.read_metadata(), Err(Error::NotBTreeFile)));
+            let mut node: Node = Node::new();
+            node.keys.push(TreeObject::new(1, 1));
+            node.number_of_keys = 1;
+            pager.write(&node).unwrap();
+            let file = OpenOptions::new().write(true).open(file_name).unwrap();
+            file.set_len(node.offset + 20).unwrap();
+            let result: Result<Node> = pager.read(node.offset);
+            assert!(matches!(result, Err(Error::Io(_))));
+            delete_file(file_name);
+        }
+
+        #[test]
+        fn test_pager_metadata_unknown_file() {
+            let file_name = "test_pager_metadata_unknown_file.tmp";
+            delete_file(file_name);
+            std::fs::write(file_name, ">sequence\nACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT\n").unwrap();
+            let mut pager = Pager::new(file_name, 2, DEFAULT_PAGE_SIZE).unwrap();
+            assert!(matches!(pager.read_metadata(), Err(Error::NotBTreeFile)));
+            delete_file(file_name);
+        }
+
+        #[test]
+        fn test_pager_metadata_version_mismatch() {
+            let file_name = "test_pager_metadata_version_mismatch.tmp";
+            delete_file(file_name);
+            let mut pager = Pager::new(file_name, 2, DEFAULT_PAGE_SIZE).unwrap();
+            let mut node: Node = Node::new();
+            pager.recreate_file(&mut node).unwrap();
+            // Version follows the magic bytes
+            let mut file = OpenOptions::new().write(true).open(file_name).unwrap();
+            file.seek(SeekFrom::Start(8)).unwrap();
+            file.write_all(&(VERSION + 1).to_be_bytes()).unwrap();
+            file.flush().unwrap();
+            let result = pager.read_metadata();
+            assert!(matches!(result, Err(Error::VersionMismatch { expected: VERSION, found }) if found == VERSION + 1));
             delete_file(file_name);
         }
 
-}
\ No newline at end of file
+        #[test]
+        fn test_pager_read_checksum_mismatch() {
+            let file_name = "test_pager_read_checksum_mismatch.tmp";
+            delete_file(file_name);
+            let mut pager = Pager::new(file_name, 2, DEFAULT_PAGE_SIZE).unwrap();
+            let mut first: Node = Node::new();
+            first.keys.push(TreeObject::new(1, 1));
+            first.number_of_keys = 1;
+            pager.write(&first).unwrap();
+            let mut second: Node = Node::new();
+            second.keys.push(TreeObject::new(1, 1));
+            second.number_of_keys = 1;
+            second.offset = pager.file_cursor;
+            pager.write(&second).unwrap();
+            // Flip a bit in the second node's key, the node still looks well formed apart from its checksum
+            let mut file = OpenOptions::new().read(true).write(true).open(file_name).unwrap();
+            let key_offset = second.offset + NODE_HEADER_SIZE as u64 + 7;
+            let mut byte = [0u8];
+            file.seek(SeekFrom::Start(key_offset)).unwrap();
+            file.read_exact(&mut byte).unwrap();
+            file.seek(SeekFrom::Start(key_offset)).unwrap();
+            file.write_all(&[byte[0] ^ 0x02]).unwrap();
+            file.flush().unwrap();
+            assert_eq!(first, pager.read(first.offset).unwrap());
+            let result: Result<Node> = pager.read(second.offset);
+            assert!(matches!(result, Err(Error::ChecksumMismatch { offset, expected, found }) if offset == second.offset && expected != found));
+            delete_file(file_name);
+        }
+
+        #[test]
+        fn test_pager_metadata_checksum_mismatch() {
+            let file_name = "test_pager_metadata_checksum_mismatch.tmp";
+            delete_file(file_name);
+            let mut pager = Pager::new(file_name, 2, DEFAULT_PAGE_SIZE).unwrap();
+            let mut node: Node = Node::new();
+            pager.recreate_file(&mut node).unwrap();
+            assert!(pager.read_metadata().is_ok());
+            // Number of keys in the header
+            let mut file = OpenOptions::new().write(true).open(file_name).unwrap();
+            file.seek(SeekFrom::Start(48)).unwrap();
+            file.write_all(&1000u64.to_be_bytes()).unwrap();
+            file.flush().unwrap();
+            let result = pager.read_metadata();
+            assert!(matches!(result, Err(Error::ChecksumMismatch { offset: 0, .. })));
+            delete_file(file_name);
+        }
+
+        #[test]
+        fn test_pager_metadata_invalid_layout() {
+            let file_name = "test_pager_metadata_invalid_layout.tmp";
+            delete_file(file_name);
+            let mut pager = Pager::new(file_name, 2, DEFAULT_PAGE_SIZE).unwrap();
+            let mut node: Node = Node::new();
+            pager.recreate_file(&mut node).unwrap();
+            // Headers with matching checksums, but a page size or degree nodes can't be laid out with
+            let mut metadata = pager.read_metadata().unwrap();
+            metadata.page_size = 0;
+            pager.write_metadata(&metadata).unwrap();
+            assert!(matches!(pager.read_metadata(), Err(Error::InvalidPageSize(0))));
+            metadata.page_size = DEFAULT_PAGE_SIZE;
+            metadata.degree = 0;
+            pager.write_metadata(&metadata).unwrap();
+            assert!(matches!(pager.read_metadata(), Err(Error::InvalidDegree(0))));
+            delete_file(file_name);
+        }
+
+        #[test]
+        fn test_optimal_degree() {
+            // u64 keys and values
+            assert_eq!(85, optimal_degree(4096, 16));
+            assert!(node_size(85, 16) <= 4096);
+            assert!(node_size(86, 16) > 4096);
+            assert_eq!(10, optimal_degree(512, 16));
+            assert!(node_size(10, 16) <= 512 && node_size(11, 16) > 512);
+            // u128 keys and [u32; 4] values
+            assert!(node_size(optimal_degree(4096, 32), 32) <= 4096);
+            assert!(node_size(optimal_degree(4096, 32) + 1, 32) > 4096);
+            assert_eq!(2, optimal_degree(64, 16));
+        }
+
+        #[test]
+        fn test_pager_allocate_out_of_order() {
+            let file_name = "test_pager_allocate_out_of_order.tmp";
+            delete_file(file_name);
+            let mut pager = Pager::new(file_name, 2, 256).unwrap();
+            let mut first: Node = Node::new();
+            first.offset = pager.allocate::<u64, u64>().unwrap();
+            let mut second: Node = Node::new();
+            second.offset = pager.allocate::<u64, u64>().unwrap();
+            assert_eq!((256, 512, 768), (first.offset, second.offset, pager.file_cursor));
+            // Writing allocated nodes, in any order, leaves the file cursor after them
+            pager.write(&second).unwrap();
+            pager.write(&first).unwrap();
+            assert_eq!(768, pager.file_cursor);
+            assert_eq!(first, pager.read(first.offset).unwrap());
+            assert_eq!(second, pager.read(second.offset).unwrap());
+            delete_file(file_name);
+        }
+
+        #[test]
+        fn test_pager_free_list() {
+            let file_name = "test_pager_free_list.tmp";
+            delete_file(file_name);
+            let mut pager = Pager::new(file_name, 2, 256).unwrap();
+            let offsets: Vec<Offset> = (0..3).map(|_| pager.allocate::<u64, u64>().unwrap()).collect();
+            for &offset in offsets.iter() {
+                let mut node: Node = Node::new();
+                node.offset = offset;
+                pager.write(&node).unwrap();
+            }
+            pager.free::<u64, u64>(offsets[0]).unwrap();
+            pager.free::<u64, u64>(offsets[2]).unwrap();
+            assert_eq!((offsets[2], 2), (pager.free_list, pager.free_pages));
+            // A free page isn't a node
+            let result: Result<Node> = pager.read(offsets[2]);
+            assert!(matches!(result, Err(Error::Corrupt { offset, expected: 1, found: 2 }) if offset == offsets[2]));
+            // Free pages are reused, most recently freed first, before the file grows
+            let cursor = pager.file_cursor;
+            assert_eq!(offsets[2], pager.allocate::<u64, u64>().unwrap());
+            assert_eq!(offsets[0], pager.allocate::<u64, u64>().unwrap());
+            assert_eq!((0, 0, cursor), (pager.free_list, pager.free_pages, pager.file_cursor));
+            assert_eq!(cursor, pager.allocate::<u64, u64>().unwrap());
+            // A node overwritten where a free page is expected is reported
+            pager.free::<u64, u64>(offsets[1]).unwrap();
+            let mut node: Node = Node::new();
+            node.offset = offsets[1];
+            pager.write(&node).unwrap();
+            let result = pager.allocate::<u64, u64>();
+            assert!(matches!(result, Err(Error::Corrupt { offset, expected: 2, found: 1 }) if offset == offsets[1]));
+            delete_file(file_name);
+        }
+
+        #[test]
+        fn test_pager_nodes_page_aligned() {
+            let file_name = "test_pager_nodes_page_aligned.tmp";
+            for (degree, pages_per_node) in [(2, 1), (10, 2)] {
+                delete_file(file_name);
+                let mut pager = Pager::new(file_name, degree, 256).unwrap();
+                let mut node: Node = Node::new();
+                pager.recreate_file(&mut node).unwrap();
+                assert_eq!(256, node.offset);
+                for i in 1..3 {
+                    let mut node: Node = Node::new();
+                    node.offset = pager.file_cursor;
+                    assert_eq!(256 + i * 256 * pages_per_node, node.offset);
+                    pager.write(&node).unwrap();
+                }
+                assert_eq!(256 + 3 * 256 * pages_per_node, pager.file_cursor);
+                assert_eq!(pager.file_cursor, std::fs::metadata(file_name).unwrap().len());
+                assert_eq!(256, pager.read_metadata().unwrap().page_size);
+            }
+            delete_file(file_name);
+        }
+
+        #[test]
+        fn test_pager_page_size_too_small() {
+            let file_name = "test_pager_page_size_too_small.tmp";
+            delete_file(file_name);
+            assert!(matches!(Pager::new(file_name, 2, 32), Err(Error::InvalidPageSize(32))));
+            delete_file(file_name);
+        }
+
+}
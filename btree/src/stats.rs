@@ -0,0 +1,30 @@
+use std::fmt;
+use crate::buffer_pool::CacheStats;
+
+/// Summary of a BTree when it was closed, returned by BTree::close once everything has been written to file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub degree: u32,
+    pub number_of_keys: u64,
+    pub number_of_nodes: u64,
+    pub height: u32,
+    /// Bytes the BTree file takes up.
+    pub file_size: u64,
+    /// Pages in the file freed by deleted nodes, waiting to be reused.
+    pub free_pages: u64,
+    /// Number of nodes the cache held besides the ones in use, None without a cache.
+    pub cache_capacity: Option<u32>,
+    /// How the cache was used since the BTree was opened, None without a cache.
+    pub cache: Option<CacheStats>,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} keys in {} nodes of degree {}, height {}, {} bytes with {} free pages",
+               self.number_of_keys, self.number_of_nodes, self.degree, self.height, self.file_size, self.free_pages)?;
+        if let (Some(capacity), Some(cache)) = (self.cache_capacity, self.cache) {
+            write!(f, ", cache of {capacity} nodes: {cache}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions, remove_file};
+use std::io::{Read, Seek, SeekFrom, Write};
+use crate::checksum::crc32c;
+use crate::error::Result;
+
+/// Bytes at the start of each record before the page image, the page's offset, the image's length and checksum.
+const RECORD_HEADER_SIZE: u64 = 16;
+/// Position of the CRC32C checksum within a record, covering the whole record with the checksum zeroed.
+const RECORD_CHECKSUM: usize = 12;
+/// Offset of the file header, a record for it commits the batch of pages logged before it.
+const HEADER_OFFSET: u64 = 0;
+
+/// Path of the write-ahead log kept alongside the BTree file file_name.
+pub fn wal_path(file_name: &str) -> String {
+    format!("{file_name}.wal")
+}
+
+/// Write-ahead log, making a batch of page writes to a BTree file atomic.
+/// Pages are logged instead of being written to the BTree file, and the batch is committed by logging the
+/// file's header after them. Only then are the pages and header copied to the BTree file and the log emptied,
+/// so if that is cut short the committed batch is copied again when the log is next opened, and a batch
+/// that was never committed is thrown away, leaving the BTree file as it was after the last commit.
+///
+/// Each record is the page's offset as a u64, the length of the page image and a CRC32C checksum of the
+/// record as u32s, then the page image. A page logged again in the same batch overwrites its record.
+pub struct Wal {
+    file: File,
+    path: String,
+    /// Position and length of the latest image of each logged page within the log
+    index: HashMap<u64, (u64, usize)>,
+    /// Length of the log, where the next record goes
+    end: u64,
+}
+
+impl Wal {
+    /// Open the log at path for the BTree file data. A batch committed to the log but not yet copied to data
+    /// is copied now, anything else in the log is thrown away.
+    pub fn open(path: &str, data: &mut File) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut wal = Wal { file, path: path.to_string(), index: HashMap::new(), end: 0 };
+        wal.recover(data)?;
+        Ok(wal)
+    }
+
+    /// Open the log at path, throwing away whatever is in it, for a BTree file that is being recreated.
+    pub fn create(path: &str) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Wal { file, path: path.to_string(), index: HashMap::new(), end: 0 })
+    }
+
+    /// Pages have been logged since the last commit.
+    pub fn uncommitted(&self) -> bool {
+        !self.index.is_empty()
+    }
+
+    /// Log the image of the page at offset, in place of the BTree file.
+    pub fn append(&mut self, offset: u64, page: &[u8]) -> Result<()> {
+        let position = match self.index.get(&offset) {
+            Some(&(position, _)) => position - RECORD_HEADER_SIZE,
+            None => self.end,
+        };
+        self.write_record(position, offset, page)?;
+        self.index.insert(offset, (position + RECORD_HEADER_SIZE, page.len()));
+        self.end = self.end.max(position + RECORD_HEADER_SIZE + page.len() as u64);
+        Ok(())
+    }
+
+    /// Read the latest image of the page at offset into buf, returning false if the page hasn't been logged.
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<bool> {
+        let Some(&(position, _)) = self.index.get(&offset) else {
+            return Ok(false);
+        };
+        self.file.seek(SeekFrom::Start(position))?;
+        self.file.read_exact(buf)?;
+        Ok(true)
+    }
+
+    /// Commit the pages logged so far along with the file header, then copy them to the BTree file data.
+    pub fn commit(&mut self, header: &[u8], data: &mut File) -> Result<()> {
+        self.log_header(header)?;
+        self.checkpoint(header, data)
+    }
+
+    /// Log the file header after the pages logged so far, committing them once it's on disk.
+    fn log_header(&mut self, header: &[u8]) -> Result<()> {
+        // Every page has to be on disk before the header that commits them
+        self.file.sync_data()?;
+        self.write_record(self.end, HEADER_OFFSET, header)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Copy the committed pages and header to the BTree file data, and empty the log once they're on disk.
+    fn checkpoint(&mut self, header: &[u8], data: &mut File) -> Result<()> {
+        // Copied in file order, so the BTree file is written front to back
+        let mut pages: Vec<(u64, (u64, usize))> = self.index.iter().map(|(&offset, &record)| (offset, record)).collect();
+        pages.sort_unstable();
+        let mut page = Vec::new();
+        for (offset, (position, page_len)) in pages {
+            page.resize(page_len, 0);
+            self.file.seek(SeekFrom::Start(position))?;
+            self.file.read_exact(&mut page)?;
+            data.seek(SeekFrom::Start(offset))?;
+            data.write_all(&page)?;
+        }
+        data.seek(SeekFrom::Start(HEADER_OFFSET))?;
+        data.write_all(header)?;
+        data.sync_data()?;
+        // Emptying the log has to reach the disk before a new batch is logged over the old one
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.index.clear();
+        self.end = 0;
+        Ok(())
+    }
+
+    /// Copy a committed batch left in the log to the BTree file data, throwing away everything else.
+    /// Records are read until one is cut short or doesn't match its checksum, the batch is committed
+    /// if the header is read before that.
+    fn recover(&mut self, data: &mut File) -> Result<()> {
+        let log_len = self.file.metadata()?.len();
+        let mut position = 0;
+        while position + RECORD_HEADER_SIZE <= log_len {
+            let mut record = vec![0u8; RECORD_HEADER_SIZE as usize];
+            self.file.seek(SeekFrom::Start(position))?;
+            self.file.read_exact(&mut record)?;
+            let offset = u64::from_be_bytes(record[0..8].try_into().unwrap());
+            let page_len = u32::from_be_bytes(record[8..12].try_into().unwrap()) as u64;
+            if position + RECORD_HEADER_SIZE + page_len > log_len {
+                break;
+            }
+            record.resize((RECORD_HEADER_SIZE + page_len) as usize, 0);
+            self.file.read_exact(&mut record[RECORD_HEADER_SIZE as usize..])?;
+            let stored = u32::from_be_bytes(record[RECORD_CHECKSUM..RECORD_CHECKSUM + 4].try_into().unwrap());
+            record[RECORD_CHECKSUM..RECORD_CHECKSUM + 4].fill(0);
+            if crc32c(&record) != stored {
+                break;
+            }
+            if offset == HEADER_OFFSET {
+                log::info!("Recovering {} pages committed to {}", self.index.len(), self.path);
+                return self.checkpoint(&record[RECORD_HEADER_SIZE as usize..], data);
+            }
+            self.index.insert(offset, (position + RECORD_HEADER_SIZE, page_len as usize));
+            position += RECORD_HEADER_SIZE + page_len;
+        }
+        if log_len > 0 {
+            log::info!("Discarding {} pages logged to {} that were never committed", self.index.len(), self.path);
+        }
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.index.clear();
+        Ok(())
+    }
+
+    /// Write a record holding page, the image of the page at offset, at position in the log.
+    fn write_record(&mut self, position: u64, offset: u64, page: &[u8]) -> Result<()> {
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE as usize + page.len());
+        record.extend_from_slice(&offset.to_be_bytes());
+        record.extend_from_slice(&(page.len() as u32).to_be_bytes());
+        record.extend_from_slice(&[0u8; 4]);
+        record.extend_from_slice(page);
+        let checksum = crc32c(&record);
+        record[RECORD_CHECKSUM..RECORD_CHECKSUM + 4].copy_from_slice(&checksum.to_be_bytes());
+        self.file.seek(SeekFrom::Start(position))?;
+        self.file.write_all(&record)?;
+        Ok(())
+    }
+}
+
+impl Drop for Wal {
+    /// Remove the log once everything in it has been committed and copied to the BTree file.
+    /// A log left with pages in it is kept, for the next open to recover from.
+    fn drop(&mut self) {
+        if self.end == 0 {
+            remove_file(&self.path).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Helper function, delete files for test cleanup
+    fn delete_file(file: &str){
+        std::fs::remove_file(file).ok();
+    }
+
+    /// Empty BTree file standing in for the one being logged, and the path of its log.
+    fn data_file(file_name: &str) -> (File, String) {
+        delete_file(file_name);
+        delete_file(&wal_path(file_name));
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(file_name).unwrap();
+        (file, wal_path(file_name))
+    }
+
+    #[test]
+    fn test_wal_commit() {
+        let file_name = "test_wal_commit.tmp";
+        let (mut data, path) = data_file(file_name);
+        let mut wal = Wal::create(&path).unwrap();
+        wal.append(16, &[1; 16]).unwrap();
+        wal.append(32, &[2; 16]).unwrap();
+        // Logging a page again replaces its record
+        wal.append(16, &[3; 16]).unwrap();
+        assert!(wal.uncommitted());
+        assert_eq!(2 * (RECORD_HEADER_SIZE + 16), std::fs::metadata(&path).unwrap().len());
+        let mut page = [0u8; 16];
+        assert!(wal.read(16, &mut page).unwrap());
+        assert_eq!([3; 16], page);
+        assert!(!wal.read(48, &mut page).unwrap());
+        // Nothing reaches the BTree file until the header commits the batch
+        assert_eq!(0, data.metadata().unwrap().len());
+        wal.commit(&[9; 16], &mut data).unwrap();
+        assert!(!wal.uncommitted());
+        assert_eq!(0, std::fs::metadata(&path).unwrap().len());
+        let expected: Vec<u8> = [[9; 16], [3; 16], [2; 16]].concat();
+        assert_eq!(expected, std::fs::read(file_name).unwrap());
+        // The log is removed once everything in it is committed
+        drop(wal);
+        assert!(!std::path::Path::new(&path).exists());
+        delete_file(file_name);
+    }
+
+    #[test]
+    fn test_wal_recover_committed() {
+        let file_name = "test_wal_recover_committed.tmp";
+        let (mut data, path) = data_file(file_name);
+        let mut wal = Wal::create(&path).unwrap();
+        wal.append(16, &[1; 16]).unwrap();
+        wal.log_header(&[9; 16]).unwrap();
+        // Crash after committing, before the batch is copied to the BTree file
+        std::mem::forget(wal);
+        assert_eq!(0, data.metadata().unwrap().len());
+        let wal = Wal::open(&path, &mut data).unwrap();
+        assert!(!wal.uncommitted());
+        assert_eq!([[9; 16], [1; 16]].concat(), std::fs::read(file_name).unwrap());
+        drop(wal);
+        assert!(!std::path::Path::new(&path).exists());
+        delete_file(file_name);
+    }
+
+    #[test]
+    fn test_wal_discard_uncommitted() {
+        let file_name = "test_wal_discard_uncommitted.tmp";
+        let (mut data, path) = data_file(file_name);
+        let mut wal = Wal::create(&path).unwrap();
+        wal.append(16, &[1; 16]).unwrap();
+        // A log with pages in it is kept for recovery
+        drop(wal);
+        assert!(std::path::Path::new(&path).exists());
+        let wal = Wal::open(&path, &mut data).unwrap();
+        assert!(!wal.uncommitted());
+        assert_eq!(0, std::fs::metadata(&path).unwrap().len());
+        assert_eq!(0, data.metadata().unwrap().len());
+        drop(wal);
+        delete_file(file_name);
+    }
+
+    #[test]
+    fn test_wal_discard_torn_record() {
+        let file_name = "test_wal_discard_torn_record.tmp";
+        let (mut data, path) = data_file(file_name);
+        let mut wal = Wal::create(&path).unwrap();
+        wal.append(16, &[1; 16]).unwrap();
+        wal.append(32, &[2; 16]).unwrap();
+        wal.log_header(&[9; 16]).unwrap();
+        std::mem::forget(wal);
+        // The second page only partly reached the disk, so the header after it can't be trusted
+        let mut log = std::fs::read(&path).unwrap();
+        log[(2 * RECORD_HEADER_SIZE + 16) as usize + 3] = 0;
+        std::fs::write(&path, &log).unwrap();
+        let wal = Wal::open(&path, &mut data).unwrap();
+        assert!(!wal.uncommitted());
+        assert_eq!(0, data.metadata().unwrap().len());
+        drop(wal);
+        delete_file(file_name);
+    }
+}
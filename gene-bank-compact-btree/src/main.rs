@@ -0,0 +1,67 @@
+use clap::Parser;
+use std::path::Path;
+use btree::{BTree, BTreeOptions};
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// btreefile file to compact, rewritten in place with its nodes full and laid out breadth first
+    #[arg(short, long)]
+    btreefile: String,
+    /// the degree of the compacted B-Tree, 0 picks the largest degree whose nodes fit in a page. Keeps the B-Tree file's degree if not given
+    #[arg(short, long)]
+    degree: Option<u32>,
+    /// sequence length to store in a B-Tree file from before the file format was versioned, which is migrated to the current one before it's compacted. These don't store their sequence length
+    #[arg(short, long)]
+    length: Option<u32>,
+    /// Enable debugging messages, optional argument with a default value of zero
+    #[arg(short = 'v', long)]
+    debug: Option<i8>,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let btreefile = cli.btreefile;
+    if cli.debug.unwrap_or(0) == 1 {
+        std::env::set_var("RUST_LOG", "debug");
+        env_logger::init();
+    }
+    // Check if btree file exists, if it doesn't panic/exit
+    if ! Path::new(&btreefile).exists() {
+        println!("{btreefile} not found.");
+        std::process::exit(1);
+    }
+    let options = BTreeOptions { use_cache: false, ..Default::default() };
+    let btree: BTree = match BTree::open(&btreefile, options) {
+        Err(btree::Error::NeedsMigration) => migrate(&btreefile, options, cli.length),
+        result => exit_on_error(&btreefile, result),
+    };
+    let before = exit_on_error(&btreefile, btree.close());
+    println!("Before: {before}");
+    let after = exit_on_error(&btreefile, btree::compact::<u64, u64>(&btreefile, cli.degree));
+    println!("After: {after}");
+    log::debug!("Compacted {btreefile} from {} to {} bytes", before.file_size, after.file_size);
+}
+
+/// Migrate a B-Tree file from before the file format was versioned to the current one, storing sequence_length
+/// in it. A k-mer held more than once has its frequencies added together.
+fn migrate(btree_file: &str, options: BTreeOptions, sequence_length: Option<u32>) -> BTree {
+    let Some(sequence_length) = sequence_length.filter(|length| (1..=31).contains(length)) else {
+        println!("{btree_file} is from before the file format was versioned, give its sequence length between 1 - 31 with --length to migrate it.");
+        std::process::exit(1);
+    };
+    let add = |stored: Option<u64>, frequency| stored.map_or(frequency, |stored| stored + frequency);
+    let version = exit_on_error(btree_file, btree::migrate::<u64, u64, _>(btree_file, options, add));
+    log::debug!("Migrated {btree_file} from file format version {version}");
+    let mut btree: BTree = exit_on_error(btree_file, BTree::open(btree_file, options));
+    exit_on_error(btree_file, btree.set_sequence_length(sequence_length));
+    btree
+}
+
+/// Unwrap result, or print the error to standard error and exit when the BTree file couldn't be compacted.
+fn exit_on_error<T>(btree_file: &str, result: btree::Result<T>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{btree_file}: {err}");
+        std::process::exit(1);
+    })
+}
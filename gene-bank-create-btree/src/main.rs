@@ -4,9 +4,8 @@ use std::fs;
 use std::io::Write;
 use std::path::Path;
 use std::fs::File;
-use btree::BTree;
-use btree::btree_node::TreeObject;
-use gene;
+use btree::{BTree, BTreeOptions, CachePolicy};
+use btree::options::parse_memory_size;
 
 
 #[derive(Parser)]
@@ -18,18 +17,33 @@ struct Cli {
     /// the degree to be used for the B-Tree. If the user specifies 0, then our program should choose the optimum degree based on a disk block size of 4096 bytes and the size of our B-Tree node on disk
     #[arg(short, long, default_value_t = 3)]
     degree: u32,
-    /// input *.gbk file containing the input DNA sequences
-    #[arg(short, long)]
-    gbkfile: String,
+    /// input *.gbk files containing the input DNA sequences, k-mers from every file are added to the same B-Tree
+    #[arg(short, long, num_args = 1.., required = true)]
+    gbkfile: Vec<String>,
     /// integer that must be between 1 and 31 (inclusive)
     #[arg(short, long, default_value_t = 10)]
     length: u32,
     /// integer between 100 and 10000 (inclusive) that represents the maximum number of BTreeNode objects that can be stored in memory
     #[arg(short = 's', long)]
     cachesize: Option<u32>,
+    /// memory the cache can use instead of a number of nodes, such as 512MiB or 64KB, the number of nodes is worked out from the memory each cached node takes up, several times its size on disk for small degrees
+    #[arg(long, value_parser = parse_memory_size, conflicts_with = "cachesize")]
+    cache_mem: Option<u64>,
+    /// cache eviction policy when the cache is used: lru (least recently used), clock, or arc (adaptive replacement cache)
+    #[arg(long, default_value_t = CachePolicy::Lru)]
+    cache_policy: CachePolicy,
+    /// size in bytes of the disk blocks B-Tree nodes are aligned to, a degree of 0 picks the largest degree whose nodes fit in one block
+    #[arg(short, long, default_value_t = 4096)]
+    pagesize: u32,
     /// Enable debugging messages, optional argument with a default value of zero
     #[arg(short = 'v', long)]
     debug: Option<u8>,
+    /// existing B-Tree file to add the k-mers to instead of creating a new one, its degree and sequence length have to match
+    #[arg(short, long)]
+    append: Option<String>,
+    /// commit the k-mers added so far to the B-Tree file every time this many more are added, as well as after each gbk file, keeping the write-ahead log smaller
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    commit_every: Option<u64>,
 }
 
 fn main() {
@@ -37,10 +51,10 @@ fn main() {
     let cli = Cli::parse();
     let cache = cli.cache;
     let degree = cli.degree;
-    let gbk_file = cli.gbkfile;
+    let gbk_files = cli.gbkfile;
     let sequence_length = cli.length;
     let cache_size = cli.cachesize.unwrap_or(100);
-    if sequence_length < 1 || sequence_length > 31 {
+    if !(1..=31).contains(&sequence_length) {
         panic!("Sequence Length has to be between 1 - 31.")
     }
     // If debug true, the dump file will want to be created.
@@ -49,41 +63,117 @@ fn main() {
         std::env::set_var("RUST_LOG", "debug");
         env_logger::init();
     }
-    // Check if gbk file exists, if it doesn't panic/exit
-    if ! Path::new(&gbk_file).exists() {
-        println!("{gbk_file} not found.");
-        std::process::exit(1);
+    // Check if gbk files exist, if they don't panic/exit
+    for gbk_file in gbk_files.iter() {
+        if ! Path::new(gbk_file).exists() {
+            println!("{gbk_file} not found.");
+            std::process::exit(1);
+        }
     }
-    // Scan gbk file
-    let dna_sequences = parse_gbk(&gbk_file).expect("No Sequences found");
-    // Get sequences into lengths
-    let chunk_sequences: Vec<String> = dna_sequences.iter().map(| x | {
-        x.chars().collect::<Vec<char>>()
-            .windows(sequence_length as usize)
-            .map(| c | c.iter().collect::<String>())
-            .collect::<Vec<String>>()
-    }).collect::<Vec<Vec<String>>>().concat();
-    // Create's too much noise in log if printing vec.
-    log::debug!("All Moving Window slices found {:?}", chunk_sequences.len());
-    let use_cache = cache == 0;
-    let output_file = format!("{gbk_file}.btree.data.{sequence_length}.{degree}");
-    //Create BTree Object
-    let mut btree = BTree::new(degree, &output_file, use_cache, cache_size, true);
-    for i in chunk_sequences.iter() {
-        // Change sequence of gene's to binary.
-        let bin_sequence = gene::sequence_to_bin(i);
-        let obj: TreeObject = TreeObject { sequence: bin_sequence, frequency: 1};
-        btree.btree_insert(obj);
+    let use_cache = cache == 1;
+    //Create BTree Object, or open the one being appended to
+    let btree_file = cli.append.clone().unwrap_or_else(|| format!("{}.btree.data.{sequence_length}.{degree}", gbk_files[0]));
+    let options = BTreeOptions {
+        degree,
+        page_size: cli.pagesize,
+        use_cache,
+        cache_size,
+        cache_memory: cli.cache_mem,
+        cache_policy: cli.cache_policy,
+        truncate_file: cli.append.is_none(),
+        read_only: false,
+    };
+    let mut btree: BTree = if cli.append.is_some() {
+        open_btree(&btree_file, options, sequence_length)
+    } else {
+        let mut btree = exit_on_error(&btree_file, BTree::open(&btree_file, options));
+        exit_on_error(&btree_file, btree.set_sequence_length(sequence_length));
+        btree
+    };
+    log::debug!("{btree_file} has degree {} and page size {}", btree.get_degree(), btree.get_page_size());
+    for gbk_file in gbk_files.iter() {
+        // Scan gbk file
+        let dna_sequences = parse_gbk(gbk_file).expect("No Sequences found");
+        // Get sequences into lengths
+        let chunk_sequences: Vec<String> = dna_sequences.iter().map(| x | {
+            x.chars().collect::<Vec<char>>()
+                .windows(sequence_length as usize)
+                .map(| c | c.iter().collect::<String>())
+                .collect::<Vec<String>>()
+        }).collect::<Vec<Vec<String>>>().concat();
+        // Create's too much noise in log if printing vec.
+        log::debug!("All Moving Window slices found in {gbk_file} {:?}", chunk_sequences.len());
+        for (inserted, i) in chunk_sequences.iter().enumerate() {
+            // Change sequence of gene's to binary.
+            let bin_sequence = gene::sequence_to_bin(i);
+            exit_on_error(&btree_file, btree.insert(bin_sequence));
+            if cli.commit_every.is_some_and(|commit_every| (inserted as u64 + 1).is_multiple_of(commit_every)) {
+                exit_on_error(&btree_file, btree.flush());
+            }
+        }
+        // Commit each file's k-mers as one batch, so if the program is killed the BTree file keeps every file
+        // finished before then. Errors are reported instead of only logged on drop.
+        exit_on_error(&btree_file, btree.flush());
+        log::debug!("Committed the k-mers from {gbk_file} to {btree_file}");
     }
-    // If debug True, create dump file with gene sequences and frequencies
+    // If debug True, report how well the cache did building the BTree, and create dump file with gene sequences and frequencies
     if debug {
-        let key_array = btree.get_sorted_array();
+        print_cache_stats(&btree_file, &btree);
         let mut file = File::create("dump").unwrap();
-        for key in key_array.iter() {
-            let line = format!("{} {}\n", gene::sequence_from_bin(key.sequence, sequence_length as u8), key.frequency);
-            file.write(line.as_bytes()).unwrap();
+        for key in btree.iter() {
+            let key = exit_on_error(&btree_file, key);
+            let line = format!("{} {}\n", gene::sequence_from_bin(key.key, sequence_length as u8), key.value);
+            file.write_all(line.as_bytes()).unwrap();
         }
     }
+    let stats = exit_on_error(&btree_file, btree.close());
+    log::debug!("Closed {btree_file} with {stats}");
+}
+
+/// Open an existing BTree file to append to, exiting if it doesn't exist or was created with a
+/// different degree or sequence length. A degree of 0 accepts whichever degree the file was created with.
+/// Files from before the file layout was versioned are migrated to the current one first.
+fn open_btree(btree_file: &str, options: BTreeOptions, sequence_length: u32) -> BTree {
+    if ! Path::new(btree_file).exists() {
+        println!("{btree_file} not found.");
+        std::process::exit(1);
+    }
+    let btree: BTree = match BTree::open(btree_file, options) {
+        // Files from before the header was versioned don't store their sequence length, and can hold a k-mer
+        // more than once, its frequencies are added together
+        Err(btree::Error::NeedsMigration) => {
+            let add = |stored: Option<u64>, frequency| stored.map_or(frequency, |stored| stored + frequency);
+            let version = exit_on_error(btree_file, btree::migrate::<u64, u64, _>(btree_file, options, add));
+            log::info!("Migrated {btree_file} from file format version {version}");
+            let mut btree = exit_on_error(btree_file, BTree::open(btree_file, options));
+            exit_on_error(btree_file, btree.set_sequence_length(sequence_length));
+            btree
+        },
+        result => exit_on_error(btree_file, result),
+    };
+    if btree.get_sequence_length() != sequence_length {
+        println!("{btree_file} has sequence length {}, not {sequence_length}.", btree.get_sequence_length());
+        // Exiting skips drop, which closes the file and removes its write-ahead log
+        drop(btree);
+        std::process::exit(1);
+    }
+    log::debug!("Appending to {btree_file} with {} keys", btree.get_size());
+    btree
+}
+
+/// Print the cache's hit rate, evictions and writebacks to standard error, for tuning cachesize.
+fn print_cache_stats(btree_file: &str, btree: &BTree) {
+    if let (Some(capacity), Some(stats)) = (btree.cache_capacity(), btree.cache_stats()) {
+        eprintln!("{btree_file} cache of {capacity} nodes: {stats}");
+    }
+}
+
+/// Unwrap result, or print the error to standard error and exit when the BTree file couldn't be read or written.
+fn exit_on_error<T>(btree_file: &str, result: btree::Result<T>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{btree_file}: {err}");
+        std::process::exit(1);
+    })
 }
 
 /// Parse the GBK file for DNA sequences
@@ -92,11 +182,11 @@ fn parse_gbk(gbk_file: &str) -> Option<Vec<String>> {
     let re = Regex::new(r"ORIGIN[^\/\/]*\/\/").unwrap();
     let sequence_break_re = Regex::new(r"n+").unwrap();
     // let sequences = contents.match_indices(&re).collect::<Vec<_>>();
-    let sequences: Vec<String> = re.find_iter(&hay.as_str()).map(|m| {
+    let sequences: Vec<String> = re.find_iter(hay.as_str()).flat_map(|m| {
         // Remove whitespace
         let mut m = m.as_str().replace(&['\n', ' ', '/'][..], "");
         // Remove line numbers
-        m = m.chars().filter(| c | !c.is_digit(10)).collect();
+        m = m.chars().filter(| c | !c.is_ascii_digit()).collect();
         // Condence sequence breaks down to one n
         m = sequence_break_re.replace_all(&m, "x").as_ref().to_string();
         // Remove starting ORIGIN
@@ -108,7 +198,7 @@ fn parse_gbk(gbk_file: &str) -> Option<Vec<String>> {
             vec![m.to_string()]
         };
         res
-    }).flatten().collect();
+    }).collect();
     log::debug!("Sequences found {:?}", sequences.len());
-    if sequences.len() == 0 { None } else { Some(sequences)}
+    if sequences.is_empty() { None } else { Some(sequences)}
 }
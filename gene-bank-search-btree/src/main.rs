@@ -1,8 +1,8 @@
 use std::fs;
 use clap::Parser;
 use std::path::Path;
-use btree::{btree_node::TreeObject, BTree};
-use gene;
+use btree::{BTree, BTreeOptions, CachePolicy};
+use btree::options::parse_memory_size;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -10,21 +10,30 @@ struct Cli {
     /// specifies whether the program should use cache (value 1) or no cache (value 0); if the value is 1, the cache-size has to be specified
     #[arg(short, long, default_value_t = 0)]
     cache: u32,
-    /// the degree to be used for the B-Tree. If the user specifies 0, then our program should choose the optimum degree based on a disk block size of 4096 bytes and the size of our B-Tree node on disk
-    #[arg(short, long, default_value_t = 0)]
-    degree: u32,
+    /// the degree of the B-Tree, read from the B-Tree file if not given. If given it must match the B-Tree file's degree, 0 accepts any degree
+    #[arg(short, long)]
+    degree: Option<u32>,
     /// input btreefile file containing the input DNA sequences in Btree
     #[arg(short, long)]
     btreefile: String,
-    /// integer that must be between 1 and 31 (inclusive)
-    #[arg(short, long, default_value_t = 10)]
-    length: u32,
-    /// contains all the DNA strings of a specific subsequence length that we want to search for in the specified B-Tree file. The strings are one per line and they all must have the same length as the DNA subsequences in the B-Tree file. The DNA strings use A, C, T, and G (either lower or upper case)
+    /// integer that must be between 1 and 31 (inclusive), read from the B-Tree file if not given. If given it must match the B-Tree file's sequence length
     #[arg(short, long)]
-    queryfile: String,
+    length: Option<u32>,
+    /// contains all the DNA strings of a specific subsequence length that we want to search for in the specified B-Tree file. The strings are one per line and they all must have the same length as the DNA subsequences in the B-Tree file. The DNA strings use A, C, T, and G (either lower or upper case)
+    #[arg(short, long, required_unless_present = "prefix")]
+    queryfile: Option<String>,
+    /// list every DNA string stored in the B-Tree file starting with this prefix, along with its frequency, instead of searching a query file. The prefix can't be longer than the subsequence length
+    #[arg(short, long, conflicts_with = "queryfile")]
+    prefix: Option<String>,
     /// integer between 100 and 10000 (inclusive) that represents the maximum number of BTreeNode objects that can be stored in memory
     #[arg(short = 's', long)]
     cachesize: Option<u32>,
+    /// memory the cache can use instead of a number of nodes, such as 512MiB or 64KB, the number of nodes is worked out from the memory each cached node takes up, several times its size on disk for small degrees
+    #[arg(long, value_parser = parse_memory_size, conflicts_with = "cachesize")]
+    cache_mem: Option<u64>,
+    /// cache eviction policy when the cache is used: lru (least recently used), clock, or arc (adaptive replacement cache)
+    #[arg(long, default_value_t = CachePolicy::Lru)]
+    cache_policy: CachePolicy,
     /// Enable debugging messages, optional argument with a default value of zero
     #[arg(short = 'v', long)]
     debug: Option<i8>,
@@ -34,17 +43,26 @@ fn main() {
     let cli = Cli::parse();
     //TODO Handle flags
     let btreefile = cli.btreefile;
-    let queryfile = cli.queryfile;
-    let sequence_length = cli.length;
-    let degree = cli.degree;
+    let degree = cli.degree.unwrap_or(0);
     let cache = cli.cache;
     let cache_size = cli.cachesize.unwrap_or(100);
-    if sequence_length < 1 || sequence_length > 31 {
+    if cli.length.is_some_and(|length| !(1..=31).contains(&length)) {
         panic!("Sequence Length has to be between 1 - 31.")
     }
 
-    let use_cache = cache == 0;
-    if cli.debug.unwrap_or(0) == 1 {
+    let use_cache = cache == 1;
+    let options = BTreeOptions {
+        degree,
+        use_cache,
+        cache_size,
+        cache_memory: cli.cache_mem,
+        cache_policy: cli.cache_policy,
+        // Searches only read the BTree file, so any number of them can run on it at once
+        read_only: true,
+        ..Default::default()
+    };
+    let debug = cli.debug.unwrap_or(0) == 1;
+    if debug {
         std::env::set_var("RUST_LOG", "debug");
         env_logger::init();
     }
@@ -53,31 +71,116 @@ fn main() {
         println!("{btreefile} not found.");
         std::process::exit(1);
     }
+    if let Some(prefix) = cli.prefix {
+        let mut btree = open_btree(&btreefile, options, cli.length);
+        let sequence_length = btree.get_sequence_length();
+        if prefix.len() > sequence_length as usize {
+            println!("Prefix {prefix} is longer than sequence length {sequence_length}.");
+            drop(btree);
+            std::process::exit(1);
+        }
+        if !gene::is_sequence(&prefix) {
+            println!("Prefix {prefix} has bases other than A, C, G and T.");
+            drop(btree);
+            std::process::exit(1);
+        }
+        exit_on_error(&btreefile, print_prefix_matches(&mut btree, &prefix, sequence_length as u8));
+        if debug {
+            print_cache_stats(&btreefile, &btree);
+        }
+        exit_on_error(&btreefile, btree.close());
+        return;
+    }
+    let queryfile = cli.queryfile.expect("Query file or prefix is required");
     // Check if queryfile file exists, if it doesn't panic/exit
     if ! Path::new(&queryfile).exists() {
         println!("{queryfile} not found.");
         std::process::exit(1);
     }
-    let mut btree = BTree::new(degree, &btreefile, use_cache, cache_size, false);
-    let query_string = fs::read_to_string(queryfile).expect("Couldn't read file ({gbk_file})");
-    for sequence in query_string.lines() {
-        let sequence_frequency = get_gene_sequence_frequency(&mut btree, sequence);
+    let mut btree = open_btree(&btreefile, options, cli.length);
+    let sequence_length = btree.get_sequence_length();
+    let query_string = fs::read_to_string(&queryfile).expect("Couldn't read file ({gbk_file})");
+    // Check every query before searching, so a bad query file doesn't give partial results
+    for (line_number, sequence) in query_string.lines().enumerate() {
+        if !sequence.is_empty() && (sequence.len() != sequence_length as usize || !gene::is_sequence(sequence)) {
+            println!("{queryfile} line {}: {sequence} isn't {sequence_length} A, C, G or T bases.", line_number + 1);
+            drop(btree);
+            std::process::exit(1);
+        }
+    }
+    for sequence in query_string.lines().filter(|line| !line.is_empty()) {
+        let sequence_frequency = exit_on_error(&btreefile, get_gene_sequence_frequency(&mut btree, sequence));
         let complement = gene::sequence_complement(sequence);
-        let complement_frequency = get_gene_sequence_frequency(&mut btree, &complement);
+        let complement_frequency = exit_on_error(&btreefile, get_gene_sequence_frequency(&mut btree, &complement));
         let frequency = sequence_frequency + complement_frequency;
         println!("{sequence} {frequency}");
     }
+    if debug {
+        print_cache_stats(&btreefile, &btree);
+    }
+    exit_on_error(&btreefile, btree.close());
 }
 
-/// Handle building TreeObject, invoking btree, and handling if nothing is returned
-fn get_gene_sequence_frequency(btree: &mut BTree, sequence: &str) -> u64 {
+/// Open the BTree file, exiting if it can't be read, or stores sequences of a different length when sequence_length is given.
+/// A degree of 0 accepts whichever degree the file was created with.
+fn open_btree(btree_file: &str, options: BTreeOptions, sequence_length: Option<u32>) -> BTree {
+    let btree: BTree = match BTree::open(btree_file, options) {
+        Err(err @ btree::Error::NeedsMigration) => {
+            eprintln!("{btree_file}: {err}, with gene-bank-compact-btree --btreefile={btree_file} --length=<sequence-length>");
+            std::process::exit(1);
+        },
+        result => exit_on_error(btree_file, result),
+    };
+    let stored_length = btree.get_sequence_length();
+    if stored_length == 0 {
+        eprintln!("{btree_file} doesn't store its sequence length.");
+        drop(btree);
+        std::process::exit(1);
+    }
+    if let Some(sequence_length) = sequence_length.filter(|length| *length != stored_length) {
+        eprintln!("{btree_file} has sequence length {stored_length}, not {sequence_length}.");
+        drop(btree);
+        std::process::exit(1);
+    }
+    log::debug!("{btree_file} has degree {} and sequence length {stored_length}", btree.get_degree());
+    btree
+}
+
+/// Handle encoding sequence, invoking btree, and handling if nothing is returned
+fn get_gene_sequence_frequency(btree: &mut BTree, sequence: &str) -> btree::Result<u64> {
     let sequence_bin = gene::sequence_to_bin(sequence);
-    let key = TreeObject {sequence: sequence_bin, frequency: 0 };
-    match btree.btree_search_root(key) {
-        Some(found_key) => found_key.frequency,
+    match btree.get(sequence_bin)? {
+        Some(frequency) => Ok(frequency),
         None => {
             log::info!("{sequence} wasn't found in btree.");
-            0
+            Ok(0)
         },
     }
 }
+
+/// Print every stored sequence starting with prefix and its frequency, found with a range scan over the btree.
+/// Frequencies are as stored, they aren't combined with the complement strand like query file searches.
+fn print_prefix_matches(btree: &mut BTree, prefix: &str, sequence_length: u8) -> btree::Result<()> {
+    let range = gene::prefix_range(prefix, sequence_length);
+    log::debug!("Prefix {prefix} covers encoded sequences {range:?}");
+    for obj in btree.range(range) {
+        let obj = obj?;
+        println!("{} {}", gene::sequence_from_bin(obj.key, sequence_length), obj.value);
+    }
+    Ok(())
+}
+
+/// Print the cache's hit rate, evictions and writebacks to standard error, for tuning cachesize.
+fn print_cache_stats(btree_file: &str, btree: &BTree) {
+    if let (Some(capacity), Some(stats)) = (btree.cache_capacity(), btree.cache_stats()) {
+        eprintln!("{btree_file} cache of {capacity} nodes: {stats}");
+    }
+}
+
+/// Unwrap result, or print the error to standard error and exit when the BTree file couldn't be read.
+fn exit_on_error<T>(btree_file: &str, result: btree::Result<T>) -> T {
+    result.unwrap_or_else(|err| {
+        eprintln!("{btree_file}: {err}");
+        std::process::exit(1);
+    })
+}
@@ -1,6 +1,5 @@
 use clap::Parser;
 use std::path::Path;
-use sqlite;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
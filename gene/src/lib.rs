@@ -1,3 +1,4 @@
+use std::ops::RangeInclusive;
 
 /// Gene Seqeuence type, represented as a u64 binary sequence
 type GeneSeq = u64;
@@ -14,8 +15,8 @@ pub fn sequence_to_bin(sequence: &str) -> GeneSeq {
         if bin_sequence == 0 {
             bin_sequence = *i;
         } else {
-            bin_sequence = bin_sequence << 2;
-            bin_sequence = bin_sequence | i;
+            bin_sequence <<= 2;
+            bin_sequence |= i;
         }
     }
     bin_sequence
@@ -32,6 +33,18 @@ pub fn sequence_from_bin(bin_sequence: GeneSeq, sequence_len: u8) -> String {
     sequence
 }
 
+/// Get the inclusive range of binary sequences, sequence_len genes long, that start with prefix.
+/// Genes are encoded most significant first, so all sequences sharing a prefix sit next to each other.
+pub fn prefix_range(prefix: &str, sequence_len: u8) -> RangeInclusive<GeneSeq> {
+    if prefix.len() > sequence_len as usize {
+        panic!("Prefix '{prefix}' is longer than sequence length {sequence_len}.");
+    }
+    let remaining_bits = 2 * (sequence_len as u32 - prefix.len() as u32);
+    let low = sequence_to_bin(prefix).checked_shl(remaining_bits).unwrap_or(0);
+    let suffix_mask = (1 as GeneSeq).checked_shl(remaining_bits).unwrap_or(0).wrapping_sub(1);
+    low..=(low | suffix_mask)
+}
+
 /// Get binary representation of gene char
 pub fn gene_to_bin(gene: char) -> u8 {
     match gene.to_ascii_lowercase() {
@@ -54,6 +67,11 @@ pub fn gene_from_bin(gene_bin: u8) -> char {
     }
 }
 
+/// Check sequence is made up only of A, T, C and G, in either case
+pub fn is_sequence(sequence: &str) -> bool {
+    sequence.chars().all(|gene| matches!(gene.to_ascii_lowercase(), 'a' | 't' | 'c' | 'g'))
+}
+
 /// Get Gene's complement
 pub fn gene_complement(gene: char) -> char {
     match gene.to_ascii_lowercase() {
@@ -68,7 +86,7 @@ pub fn gene_complement(gene: char) -> char {
 /// Get sequence of gene complements
 pub fn sequence_complement(gene: &str) -> String {
     let gene_chars = gene.chars();
-    gene_chars.map(| x | gene_complement(x)).collect()
+    gene_chars.map(gene_complement).collect()
 }
 
 #[cfg(test)]
@@ -111,4 +129,29 @@ mod tests {
         let seq_expected = "ACTTG";
         assert_eq!(seq_expected, sequence_from_bin(seq_bin, 5));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_prefix_range() {
+        assert_eq!(0b0001_0000..=0b0001_1111, prefix_range("AC", 4));
+        assert_eq!(0b1011..=0b1011, prefix_range("gt", 2));
+        assert_eq!(0..=0b11_1111, prefix_range("", 3));
+        assert_eq!(0..=u64::MAX, prefix_range("", 32));
+    }
+
+    #[test]
+    fn test_prefix_range_contains_sequences() {
+        let range = prefix_range("ACG", 5);
+        assert!(range.contains(&sequence_to_bin("ACGAA")));
+        assert!(range.contains(&sequence_to_bin("ACGTT")));
+        assert!(!range.contains(&sequence_to_bin("ACCTT")));
+        assert!(!range.contains(&sequence_to_bin("ACTAA")));
+    }
+
+    #[test]
+    fn test_is_sequence() {
+        assert!(is_sequence("ACGTacgt"));
+        assert!(is_sequence(""));
+        assert!(!is_sequence("ACNGT"));
+        assert!(!is_sequence("ACG T"));
+    }
+}